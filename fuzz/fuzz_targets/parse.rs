@@ -0,0 +1,20 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use vhdx_inspector::VhdxFile;
+
+const FILE_LENGTH_ALIGNMENT: usize = 1024 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+	// Inputs are padded to a whole number of MiB, otherwise nearly all of them stop at the file length check.
+	let mut image = data.to_vec();
+	image.resize(data.len().div_ceil(FILE_LENGTH_ALIGNMENT).max(1) * FILE_LENGTH_ALIGNMENT, 0);
+
+	// Almost every input is rejected with an error, only a panic is a failure.
+	let _ = VhdxFile::from_reader(Cursor::new(image), true);
+});
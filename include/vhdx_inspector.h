@@ -0,0 +1,37 @@
+/* SPDX-License-Identifier: MIT */
+/* Copyright (c) Nick Moss. */
+
+#ifndef VHDX_INSPECTOR_H
+#define VHDX_INSPECTOR_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Codes match the command line exit codes, 0 is success. */
+#define VHDX_OK 0
+
+typedef struct VhdxHandle VhdxHandle;
+
+typedef struct VhdxErrorInfo
+{
+	int code;
+	/* Borrowed, valid until the next failing call on the same thread. Null on success. */
+	const char *message;
+} VhdxErrorInfo;
+
+VhdxHandle *vhdx_open(const char *path, VhdxErrorInfo *error_info);
+void vhdx_free(VhdxHandle *handle);
+
+uint64_t vhdx_virtual_size(const VhdxHandle *handle);
+uint32_t vhdx_logical_sector_size(const VhdxHandle *handle);
+uint32_t vhdx_physical_sector_size(const VhdxHandle *handle);
+uint64_t vhdx_block_count(const VhdxHandle *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
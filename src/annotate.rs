@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::io::{Read, Seek};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::Fallible;
+use crate::json;
+use crate::file_header::{FILE_HEADER_OFFSET,FILE_HEADER_SIG};
+use crate::metadata::{Metadata,MetadataTable,MetadataType};
+use crate::reader::read_into;
+use crate::region::{RegionTable,RegionTableEntry,RegionType,FIRST_REGION_TAB_OFFSET,SECOND_REGION_TAB_OFFSET,MAX_REGION_ENTRIES,REGION_TAB_HEADER_SIG};
+use crate::vhd_header::{FIRST_HEADER_OFFSET,SECOND_HEADER_OFFSET,VHD_HEADER_SIG};
+
+const ANNOTATE_BAT_SAMPLE_LEN: usize = 16;
+const BAT_ENTRY_SIZE: usize = 0x8;
+
+const METADATA_TABLE_ENTRY_OFFSET: usize = 0x20;
+const METADATA_TABLE_ENTRY_LEN: usize = 0x20;
+const REGION_TABLE_ENTRY_COUNT_OFFSET: usize = 0x8;
+const REGION_TABLE_ENTRY_OFFSET: usize = 0x10;
+const REGION_TABLE_ENTRY_LEN: usize = 0x20;
+const PARENT_LOCATOR_ENTRY_OFFSET: usize = 0x14;
+const PARENT_LOCATOR_ENTRY_LEN: usize = 0xc;
+const FILE_HEADER_DUMP_LEN: usize = 0x208;
+const VHDX_HEADER_DUMP_LEN: usize = 0x50;
+
+#[derive(PartialEq, Clone, Copy)]
+enum FieldKind
+{
+	Signature,
+	U16,
+	U32,
+	U64,
+	Guid,
+	Utf16,
+	BatEntry,
+}
+
+struct FieldLayout
+{
+	name: &'static str,
+	offset: usize,
+	length: usize,
+	kind: FieldKind,
+}
+
+const fn field(name: &'static str, offset: usize, length: usize, kind: FieldKind) -> FieldLayout
+{
+	return FieldLayout{name, offset, length, kind};
+}
+
+const FILE_HEADER_FIELDS: [FieldLayout; 2] = [
+	field("signature", 0x0, 0x8, FieldKind::Signature),
+	field("creator", 0x8, 0x200, FieldKind::Utf16),
+];
+
+const VHDX_HEADER_FIELDS: [FieldLayout; 10] = [
+	field("signature", 0x0, 0x4, FieldKind::Signature),
+	field("checksum", 0x4, 0x4, FieldKind::U32),
+	field("sequence_number", 0x8, 0x8, FieldKind::U64),
+	field("file_write_guid", 0x10, 0x10, FieldKind::Guid),
+	field("data_write_guid", 0x20, 0x10, FieldKind::Guid),
+	field("log_guid", 0x30, 0x10, FieldKind::Guid),
+	field("log_version", 0x40, 0x2, FieldKind::U16),
+	field("version", 0x42, 0x2, FieldKind::U16),
+	field("log_length", 0x44, 0x4, FieldKind::U32),
+	field("log_offset", 0x48, 0x8, FieldKind::U64),
+];
+
+const REGION_TABLE_HEADER_FIELDS: [FieldLayout; 3] = [
+	field("signature", 0x0, 0x4, FieldKind::Signature),
+	field("checksum", 0x4, 0x4, FieldKind::U32),
+	field("entry_count", 0x8, 0x4, FieldKind::U32),
+];
+
+const REGION_TABLE_ENTRY_FIELDS: [FieldLayout; 4] = [
+	field("guid", 0x0, 0x10, FieldKind::Guid),
+	field("file_offset", 0x10, 0x8, FieldKind::U64),
+	field("length", 0x18, 0x4, FieldKind::U32),
+	field("required", 0x1c, 0x4, FieldKind::U32),
+];
+
+const METADATA_TABLE_HEADER_FIELDS: [FieldLayout; 2] = [
+	field("signature", 0x0, 0x8, FieldKind::Signature),
+	field("entry_count", 0xa, 0x2, FieldKind::U16),
+];
+
+const METADATA_TABLE_ENTRY_FIELDS: [FieldLayout; 4] = [
+	field("item_id", 0x0, 0x10, FieldKind::Guid),
+	field("offset", 0x10, 0x4, FieldKind::U32),
+	field("length", 0x14, 0x4, FieldKind::U32),
+	field("flags", 0x18, 0x4, FieldKind::U32),
+];
+
+const FILE_PARAMETERS_FIELDS: [FieldLayout; 2] = [
+	field("block_size", 0x0, 0x4, FieldKind::U32),
+	field("flags", 0x4, 0x4, FieldKind::U32),
+];
+
+const VIRTUAL_DISK_SIZE_FIELDS: [FieldLayout; 1] = [field("virtual_disk_size", 0x0, 0x8, FieldKind::U64)];
+const VIRTUAL_DISK_ID_FIELDS: [FieldLayout; 1] = [field("virtual_disk_id", 0x0, 0x10, FieldKind::Guid)];
+const LOGICAL_SECTOR_SIZE_FIELDS: [FieldLayout; 1] = [field("logical_sector_size", 0x0, 0x4, FieldKind::U32)];
+const PHYSICAL_SECTOR_SIZE_FIELDS: [FieldLayout; 1] = [field("physical_sector_size", 0x0, 0x4, FieldKind::U32)];
+
+const PARENT_LOCATOR_HEADER_FIELDS: [FieldLayout; 2] = [
+	field("locator_type", 0x0, 0x10, FieldKind::Guid),
+	field("key_value_count", 0x12, 0x2, FieldKind::U16),
+];
+
+const PARENT_LOCATOR_ENTRY_FIELDS: [FieldLayout; 4] = [
+	field("key_offset", 0x0, 0x4, FieldKind::U32),
+	field("value_offset", 0x4, 0x4, FieldKind::U32),
+	field("key_length", 0x8, 0x2, FieldKind::U16),
+	field("value_length", 0xa, 0x2, FieldKind::U16),
+];
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct FieldAnnotation
+{
+	pub name: String,
+	pub offset: u64,
+	pub length: usize,
+	#[serde(serialize_with = "json::hex_bytes")]
+	pub raw: Vec<u8>,
+	pub value: String,
+}
+
+#[derive(Serialize)]
+struct AnnotationReport<'a>
+{
+	fields: &'a [FieldAnnotation],
+}
+
+pub struct RawStructure
+{
+	pub name: String,
+	pub offset: usize,
+	pub signature: &'static [u8],
+	pub raw: Vec<u8>,
+	pub fields: Vec<FieldAnnotation>,
+}
+
+impl RawStructure
+{
+	pub fn signature_valid(self: &Self) -> bool
+	{
+		return self.raw.starts_with(self.signature);
+	}
+}
+
+fn le_value(raw: &[u8]) -> u64
+{
+	return raw.iter().rev().fold(0, |acc, x| (acc << 8) | *x as u64);
+}
+
+fn decode_value(kind: FieldKind, raw: &[u8]) -> Fallible<String>
+{
+	return Ok(match kind
+	{
+		FieldKind::Signature => String::from_utf8_lossy(raw).into_owned(),
+		FieldKind::U16 | FieldKind::U32 | FieldKind::U64 => format!("0x{:X}", le_value(raw)),
+		FieldKind::Guid => Uuid::from_slice_le(raw)?.to_string(),
+		FieldKind::Utf16 =>
+		{
+			let chars: Vec<u16> = raw.chunks_exact(2).map(|x| x[0] as u16 | (x[1] as u16) << 8).collect();
+			String::from_utf16_lossy(&chars).trim_end_matches('\0').to_string()
+		},
+		FieldKind::BatEntry =>
+		{
+			let value = le_value(raw);
+			format!("state {}, offset {}MiB", value & 0b111, value >> 20)
+		},
+	});
+}
+
+fn annotate_field(data: &mut (impl Read + Seek), prefix: &str, base: u64, layout: &FieldLayout, annotations: &mut Vec<FieldAnnotation>) -> Fallible<()>
+{
+	let mut raw: Vec<u8> = vec![0;layout.length];
+	read_into(data, base + layout.offset as u64, &mut raw)?;
+	let value = decode_value(layout.kind, &raw)?;
+
+	annotations.push(FieldAnnotation{name: format!("{}.{}", prefix, layout.name), offset: base + layout.offset as u64,
+		length: layout.length, raw, value});
+	return Ok(());
+}
+
+fn annotate_fields(data: &mut (impl Read + Seek), prefix: &str, base: u64, layouts: &[FieldLayout], annotations: &mut Vec<FieldAnnotation>) -> Fallible<()>
+{
+	for layout in layouts
+	{
+		annotate_field(data, prefix, base, layout, annotations)?;
+	}
+	return Ok(());
+}
+
+fn annotate_region_table(data: &mut (impl Read + Seek), prefix: &str, table_offset: usize, table: &RegionTable, annotations: &mut Vec<FieldAnnotation>) -> Fallible<()>
+{
+	annotate_fields(data, prefix, table_offset as u64, &REGION_TABLE_HEADER_FIELDS, annotations)?;
+	for n in 0..table.entries.len()
+	{
+		annotate_fields(data, &format!("{}.entry[{}]", prefix, n),
+			(table_offset + REGION_TABLE_ENTRY_OFFSET + (n * REGION_TABLE_ENTRY_LEN)) as u64, &REGION_TABLE_ENTRY_FIELDS, annotations)?;
+	}
+	return Ok(());
+}
+
+fn annotate_metadata(data: &mut (impl Read + Seek), metadata_region: &RegionTableEntry, table: &MetadataTable, metadata: &Metadata,
+	annotations: &mut Vec<FieldAnnotation>) -> Fallible<()>
+{
+	let table_offset = metadata_region.object_offset;
+	annotate_fields(data, "metadata_table", table_offset, &METADATA_TABLE_HEADER_FIELDS, annotations)?;
+
+	for (n, entry) in table.entries.iter().enumerate()
+	{
+		annotate_fields(data, &format!("metadata_table.entry[{}]", n),
+			table_offset + (METADATA_TABLE_ENTRY_OFFSET + (n * METADATA_TABLE_ENTRY_LEN)) as u64, &METADATA_TABLE_ENTRY_FIELDS, annotations)?;
+
+		let item_offset = table_offset + entry.object_offset as u64;
+		match entry.metadata_type
+		{
+			MetadataType::FileParameters => annotate_fields(data, "file_parameters", item_offset, &FILE_PARAMETERS_FIELDS, annotations)?,
+			MetadataType::VirtualDiskSize => annotate_fields(data, "metadata", item_offset, &VIRTUAL_DISK_SIZE_FIELDS, annotations)?,
+			MetadataType::VirtualDiskId => annotate_fields(data, "metadata", item_offset, &VIRTUAL_DISK_ID_FIELDS, annotations)?,
+			MetadataType::LogicalSectorSize => annotate_fields(data, "metadata", item_offset, &LOGICAL_SECTOR_SIZE_FIELDS, annotations)?,
+			MetadataType::PhysicalSectorSize => annotate_fields(data, "metadata", item_offset, &PHYSICAL_SECTOR_SIZE_FIELDS, annotations)?,
+			MetadataType::ParentLocator =>
+			{
+				annotate_fields(data, "parent_locator", item_offset, &PARENT_LOCATOR_HEADER_FIELDS, annotations)?;
+				if let Some(locator) = &metadata.parent_locator_dict
+				{
+					for (m, locator_entry) in locator.entries.iter().enumerate()
+					{
+						let prefix = format!("parent_locator.entry[{}]", m);
+						annotate_fields(data, &prefix, item_offset + (PARENT_LOCATOR_ENTRY_OFFSET + (m * PARENT_LOCATOR_ENTRY_LEN)) as u64,
+							&PARENT_LOCATOR_ENTRY_FIELDS, annotations)?;
+						annotate_field(data, &prefix, item_offset,
+							&field("key", locator_entry.key_offset as usize, locator_entry.key_length as usize, FieldKind::Utf16), annotations)?;
+						annotate_field(data, &prefix, item_offset,
+							&field("value", locator_entry.value_offset as usize, locator_entry.value_length as usize, FieldKind::Utf16), annotations)?;
+					}
+				}
+			},
+			MetadataType::Unknown => {},
+		}
+	}
+
+	return Ok(());
+}
+
+fn annotate_bat_sample(data: &mut (impl Read + Seek), bat_region: &RegionTableEntry, entry_count: usize, annotations: &mut Vec<FieldAnnotation>) -> Fallible<()>
+{
+	let sample_len = entry_count.min(ANNOTATE_BAT_SAMPLE_LEN).min(bat_region.object_length as usize / BAT_ENTRY_SIZE);
+	for n in 0..sample_len
+	{
+		annotate_field(data, &format!("bat.entry[{}]", n), bat_region.object_offset,
+			&field("value", n * BAT_ENTRY_SIZE, BAT_ENTRY_SIZE, FieldKind::BatEntry), annotations)?;
+	}
+	return Ok(());
+}
+
+pub fn annotate_file(data: &mut (impl Read + Seek), header_offset: usize, region_table: &RegionTable, metadata_table: &MetadataTable,
+	metadata: &Metadata, bat_entry_count: usize) -> Fallible<Vec<FieldAnnotation>>
+{
+	let metadata_region = region_table.find_required(RegionType::Metadata)?;
+	let bat_region = region_table.find_required(RegionType::BAT)?;
+	let mut annotations = Vec::<FieldAnnotation>::new();
+
+	annotate_fields(data, "file_header", 0, &FILE_HEADER_FIELDS, &mut annotations)?;
+	annotate_fields(data, "vhdx_header", header_offset as u64, &VHDX_HEADER_FIELDS, &mut annotations)?;
+	annotate_region_table(data, "region_table[0]", FIRST_REGION_TAB_OFFSET, region_table, &mut annotations)?;
+	annotate_region_table(data, "region_table[1]", SECOND_REGION_TAB_OFFSET, region_table, &mut annotations)?;
+	annotate_metadata(data, metadata_region, metadata_table, metadata, &mut annotations)?;
+	annotate_bat_sample(data, bat_region, bat_entry_count, &mut annotations)?;
+
+	return Ok(annotations);
+}
+
+fn annotate_raw_structure(data: &mut (impl Read + Seek), name: &str, offset: usize, length: usize, signature: &'static [u8],
+	layouts: &[FieldLayout]) -> Fallible<RawStructure>
+{
+	let mut raw: Vec<u8> = vec![0;length];
+	read_into(data, offset as u64, &mut raw)?;
+	let mut fields = Vec::<FieldAnnotation>::new();
+	annotate_fields(data, name, offset as u64, layouts, &mut fields)?;
+
+	return Ok(RawStructure{name: name.to_string(), offset, signature, raw, fields});
+}
+
+fn annotate_raw_region_table(data: &mut (impl Read + Seek), name: &str, offset: usize) -> Fallible<RawStructure>
+{
+	let mut structure = annotate_raw_structure(data, name, offset, REGION_TABLE_ENTRY_OFFSET, &REGION_TAB_HEADER_SIG, &REGION_TABLE_HEADER_FIELDS)?;
+
+	// The entry count comes from the raw bytes so that a table which fails to parse can still be dumped.
+	let entry_count = le_value(&structure.raw[REGION_TABLE_ENTRY_COUNT_OFFSET..REGION_TABLE_ENTRY_OFFSET]).min(MAX_REGION_ENTRIES as u64) as usize;
+	structure.raw.resize(REGION_TABLE_ENTRY_OFFSET + (entry_count * REGION_TABLE_ENTRY_LEN), 0);
+	read_into(data, offset as u64, &mut structure.raw)?;
+	for n in 0..entry_count
+	{
+		annotate_fields(data, &format!("{}.entry[{}]", name, n), (offset + REGION_TABLE_ENTRY_OFFSET + (n * REGION_TABLE_ENTRY_LEN)) as u64,
+			&REGION_TABLE_ENTRY_FIELDS, &mut structure.fields)?;
+	}
+
+	return Ok(structure);
+}
+
+pub fn annotate_fixed_headers(data: &mut (impl Read + Seek)) -> Fallible<Vec<RawStructure>>
+{
+	return Ok(vec![
+		annotate_raw_structure(data, "file_header", FILE_HEADER_OFFSET, FILE_HEADER_DUMP_LEN, &FILE_HEADER_SIG, &FILE_HEADER_FIELDS)?,
+		annotate_raw_structure(data, "vhdx_header[0]", FIRST_HEADER_OFFSET, VHDX_HEADER_DUMP_LEN, &VHD_HEADER_SIG, &VHDX_HEADER_FIELDS)?,
+		annotate_raw_structure(data, "vhdx_header[1]", SECOND_HEADER_OFFSET, VHDX_HEADER_DUMP_LEN, &VHD_HEADER_SIG, &VHDX_HEADER_FIELDS)?,
+		annotate_raw_region_table(data, "region_table[0]", FIRST_REGION_TAB_OFFSET)?,
+		annotate_raw_region_table(data, "region_table[1]", SECOND_REGION_TAB_OFFSET)?,
+	]);
+}
+
+pub fn annotations_to_json(annotations: &[FieldAnnotation]) -> Fallible<String>
+{
+	return Ok(serde_json::to_string(&AnnotationReport{fields: annotations})?);
+}
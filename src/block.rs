@@ -3,24 +3,34 @@
 
 use std::{
 	fmt,
-	fs::File,
-	io::{Seek, Read, SeekFrom},
+	io::{Seek, Read, SeekFrom, Write},
 };
 
-use failure::{ensure,Fallible};
+use serde::Serialize;
 
+use crate::error::{Fallible,VhdxError};
+use crate::{ensure,format_err};
+use crate::json;
 use crate::maths::*;
 use crate::metadata::Metadata;
-use crate::region::{RegionTableEntry,RegionType};
-use crate::reader::ReadValue;
+use crate::region::{check_region_in_file,RegionTableEntry,RegionType};
+use crate::reader::{read_into,ReadValue};
 
 const CHUNK_RATIO_MULTIPLIER: u64 = 2_u32.pow(23) as u64;
+const BLOCK_OFFSET_FACTOR: u64 = u64::pow(1024, 2);
+const SECTOR_BITMAP_BLOCK_LEN: u64 = u64::pow(1024, 2);
+const MIN_PAYLOAD_OFFSET: u64 = u64::pow(1024, 2);
 
-const BAT_ENTRY_LEN: usize = 0x20;
+const BAT_ENTRY_LEN: usize = 0x8;
+const BAT_PROGRESS_INTERVAL: u64 = 0x1000;
 const BAT_ENTRY_STATE_MASK: u64 = 0b0000000000000000000000000000000000000000000000000000000000000111;
 const BAT_ENTRY_OFFSET_MASK: u64 = 0b1111111111111111111111111111111111111111111100000000000000000000;
 
-#[derive(PartialEq, Default)]
+pub const PAYLOAD_BLOCK_STATES: [PayloadBlockState; 6] = [PayloadBlockState::NotPresent, PayloadBlockState::Undefined, PayloadBlockState::Zero,
+	PayloadBlockState::Unmapped, PayloadBlockState::FullyPresent, PayloadBlockState::PartiallyPresent];
+pub const SECTOR_BLOCK_STATES: [SectorBlockState; 2] = [SectorBlockState::NotPresent, SectorBlockState::Present];
+
+#[derive(PartialEq, Debug, Default, Clone, Copy, Serialize)]
 pub enum PayloadBlockState
 {
 	#[default]
@@ -63,7 +73,7 @@ impl fmt::Display for PayloadBlockState {
 	}
 }
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub enum SectorBlockState
 {
 	#[default]
@@ -94,55 +104,61 @@ impl fmt::Display for SectorBlockState {
 	}
 }
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct PayloadEntry
 {
 	pub state: PayloadBlockState,
-	pub file_offset_mb: u64
+	#[serde(serialize_with = "json::dec_hex")]
+	pub file_offset_mb: u64,
+	#[serde(serialize_with = "json::hex")]
+	pub raw: u64,
 }
 
 impl PayloadEntry
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), index: u64, offset: u64) -> Fallible<Self>
 	{
 		let mut value: u64 = 0;
-		value.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read BAT entry bit field: {:?}", error)});
+		value.read_value(data).map_err(|error| VhdxError::read("BAT entry bit field", offset, error))?;
 
 		let mut result = PayloadEntry::default();
-		result.state = PayloadBlockState::try_from(value & BAT_ENTRY_STATE_MASK).unwrap_or_else(|_| {
-			panic!("Value {:?} is not a valid PayloadBlockState", value & BAT_ENTRY_STATE_MASK)});
+		result.raw = value;
+		result.state = PayloadBlockState::try_from(value & BAT_ENTRY_STATE_MASK).map_err(|_| {
+			format_err!("Payload block {} has invalid state {} (raw 0x{:016X}).", index, value & BAT_ENTRY_STATE_MASK, value)})?;
 		result.file_offset_mb = (value & BAT_ENTRY_OFFSET_MASK) >> 20;
 
-		return result;
+		return Ok(result);
 	}
 }
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct SectorEntry
 {
 	pub state: SectorBlockState,
-	pub file_offset_mb: u64
+	#[serde(serialize_with = "json::dec_hex")]
+	pub file_offset_mb: u64,
+	#[serde(serialize_with = "json::hex")]
+	pub raw: u64,
 }
 
 impl SectorEntry
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), index: u64, offset: u64) -> Fallible<Self>
 	{
 		let mut value: u64 = 0;
-		value.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read BAT entry bit field: {:?}", error)});
+		value.read_value(data).map_err(|error| VhdxError::read("BAT entry bit field", offset, error))?;
 
 		let mut result = SectorEntry::default();
-		result.state = SectorBlockState::try_from(value & BAT_ENTRY_STATE_MASK).unwrap_or_else(|_| {
-			panic!("Value {:?} is not a valid SectorBlockState", value & BAT_ENTRY_STATE_MASK)});
+		result.raw = value;
+		result.state = SectorBlockState::try_from(value & BAT_ENTRY_STATE_MASK).map_err(|_| {
+			format_err!("Sector bitmap block {} has invalid state {} (raw 0x{:016X}).", index, value & BAT_ENTRY_STATE_MASK, value)})?;
 		result.file_offset_mb = (value & BAT_ENTRY_OFFSET_MASK) >> 20;
 
-		return result;
+		return Ok(result);
 	}
 }
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone)]
 pub struct FileBlockValues
 {
 	pub chunk_ratio: u64,
@@ -155,56 +171,560 @@ fn calculate_block_values(file_data: &Metadata) -> Fallible<FileBlockValues>
 {
 	let chunk_ratio: u64 = (CHUNK_RATIO_MULTIPLIER * file_data.logical_sector_size as u64) / file_data.file_parameters.block_size as u64;
 	ensure!(chunk_ratio != 0, "Chunk ratio calculation resulted in 0, cannot calculate BAT.");
-	let payload_blocks = u64::ceiling_divide(file_data.virtual_disk_size as u64, file_data.file_parameters.block_size as u64);
-	let sector_blocks = u64::ceiling_divide(payload_blocks, chunk_ratio as u64);
-	let total_bat_entries;
-	if file_data.parent_locator.is_some()
+	// Sector bitmap entries are interleaved every chunk_ratio payload entries, which the spec only defines for powers of two.
+	ensure!(chunk_ratio.is_power_of_two(), "Chunk ratio 0x{:X} from logical sector size 0x{:X} and block size 0x{:X} is not a power of two, cannot calculate BAT.",
+		chunk_ratio, file_data.logical_sector_size, file_data.file_parameters.block_size);
+	let payload_blocks = u64::ceiling_divide(file_data.virtual_disk_size, file_data.file_parameters.block_size as u64);
+	let sector_blocks = u64::ceiling_divide(payload_blocks, chunk_ratio);
+	let total_bat_entries = if file_data.parent_locator.is_some()
 	{
-		// Fixed or dynamic disk calculation
-		total_bat_entries = payload_blocks + u64::floor_divide(payload_blocks - 1, chunk_ratio as u64);
+		// Differencing disk calculation
+		sector_blocks * (chunk_ratio + 1)
 	}
 	else
 	{
-		// Differencing disk calculation
-		total_bat_entries = sector_blocks * (chunk_ratio + 1);
-	}
+		// Fixed or dynamic disk calculation, an empty disk has no entries rather than underflowing.
+		payload_blocks + u64::floor_divide(payload_blocks.saturating_sub(1), chunk_ratio)
+	};
 
 	return Ok(FileBlockValues{chunk_ratio, payload_blocks, sector_blocks, total_bat_entries});
 }
 
-fn read_bat_table(data: &mut File, bat_region: &RegionTableEntry, block_values: &FileBlockValues, has_sectors: bool) -> Fallible<(Vec<PayloadEntry>,Vec<SectorEntry>)>
+pub enum BatEntry
+{
+	Payload(u64, PayloadEntry),
+	Sector(u64, SectorEntry),
+}
+
+pub struct BatIterator<R: Read + Seek>
+{
+	data: R,
+	bat_offset: u64,
+	block_values: FileBlockValues,
+	entry_index: u64,
+	payload_index: u64,
+	sector_index: u64,
+}
+
+impl<R: Read + Seek> BatIterator<R>
+{
+	// The offset of the entry just read, entry_index having already moved past it.
+	fn entry_offset(self: &Self) -> u64
+	{
+		return self.bat_offset + ((self.entry_index - 1) * BAT_ENTRY_LEN as u64);
+	}
+
+	fn read_next(self: &mut Self) -> Fallible<Option<BatEntry>>
+	{
+		// Every chunk_ratio payload entries are followed by one sector bitmap entry, whatever the disk type.
+		while self.entry_index < self.block_values.total_bat_entries
+		{
+			self.entry_index += 1;
+			if self.entry_index.is_multiple_of(self.block_values.chunk_ratio + 1)
+			{
+				self.sector_index += 1;
+				let offset = self.entry_offset();
+				let entry = SectorEntry::new(&mut self.data, self.sector_index - 1, offset)?;
+				return Ok(Some(BatEntry::Sector(self.sector_index - 1, entry)));
+			}
+
+			let offset = self.entry_offset();
+			let entry = PayloadEntry::new(&mut self.data, self.payload_index, offset)?;
+			// The last chunk of a differencing disk is padded out past the end of the virtual disk.
+			if self.payload_index < self.block_values.payload_blocks
+			{
+				self.payload_index += 1;
+				return Ok(Some(BatEntry::Payload(self.payload_index - 1, entry)));
+			}
+		}
+
+		return Ok(None);
+	}
+}
+
+impl<R: Read + Seek> Iterator for BatIterator<R>
+{
+	type Item = Fallible<BatEntry>;
+
+	fn next(&mut self) -> Option<Self::Item>
+	{
+		return self.read_next().transpose();
+	}
+}
+
+fn check_bat_length_values(bat_region: &RegionTableEntry, block_values: &FileBlockValues) -> Fallible<()>
+{
+	let bat_length = block_values.total_bat_entries * BAT_ENTRY_LEN as u64;
+	ensure!(bat_length <= bat_region.object_length as u64, "BAT region is 0x{:X} bytes but the disk geometry needs {} entries (0x{:X} bytes).",
+		bat_region.object_length, block_values.total_bat_entries, bat_length);
+
+	return Ok(());
+}
+
+pub fn check_bat_length(bat_region: &RegionTableEntry, file_data: &Metadata) -> Fallible<()>
+{
+	return check_bat_length_values(bat_region, &calculate_block_values(file_data)?);
+}
+
+pub fn iterate_bat<R: Read + Seek>(mut data: R, bat_region: &RegionTableEntry, file_data: &Metadata) -> Fallible<BatIterator<R>>
 {
+	ensure!(bat_region.region_type == RegionType::BAT, "Passed region data is not for the BAT region.");
+
+	let block_values = calculate_block_values(file_data)?;
+	check_bat_length_values(bat_region, &block_values)?;
 	data.seek(SeekFrom::Start(bat_region.object_offset))?;
 
+	return Ok(BatIterator{data, bat_offset: bat_region.object_offset, block_values, entry_index: 0, payload_index: 0, sector_index: 0});
+}
+
+pub fn allocated_payload_bytes<'a>(payload_blocks: impl IntoIterator<Item = &'a PayloadEntry>, block_size: u32) -> u64
+{
+	let allocated = payload_blocks.into_iter().filter(|x| x.state == PayloadBlockState::FullyPresent || x.state == PayloadBlockState::PartiallyPresent).count();
+	return allocated as u64 * block_size as u64;
+}
+
+// The file offset of a byte within a block, or None when a corrupt BAT offset puts it past the largest possible file offset.
+pub fn block_file_offset(entry: &PayloadEntry, block_offset: u64) -> Option<u64>
+{
+	return entry.file_offset_mb.checked_mul(BLOCK_OFFSET_FACTOR).and_then(|x| x.checked_add(block_offset));
+}
+
+pub fn virtual_to_physical(metadata: &Metadata, payload_blocks: &[PayloadEntry], virtual_offset: u64) -> Option<u64>
+{
+	let block_size = metadata.file_parameters.block_size as u64;
+	if block_size == 0 || virtual_offset >= metadata.virtual_disk_size
+	{
+		return None;
+	}
+
+	// A partially present block still has its data at this offset for the sectors set in its bitmap.
+	let entry = payload_blocks.get((virtual_offset / block_size) as usize)?;
+	match entry.state
+	{
+		PayloadBlockState::FullyPresent | PayloadBlockState::PartiallyPresent =>
+			return block_file_offset(entry, virtual_offset % block_size),
+		_ => return None,
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
+pub struct FragmentationStats
+{
+	pub present_blocks: u64,
+	pub runs: u64,
+	pub average_run_length: f64,
+	pub largest_gap_mb: u64,
+	pub ascending: bool,
+}
+
+pub fn fragmentation_stats(payload_blocks: &[PayloadEntry], block_size: u32) -> FragmentationStats
+{
+	let mut offsets: Vec<u64> = payload_blocks.iter()
+		.filter(|x| x.state == PayloadBlockState::FullyPresent || x.state == PayloadBlockState::PartiallyPresent)
+		.map(|x| x.file_offset_mb).collect();
+	// A disk written once from start to end keeps its blocks in the same order in the file as on the virtual disk.
+	let ascending = offsets.windows(2).all(|x| x[0] < x[1]);
+	offsets.sort_unstable();
+
+	let block_mb = u32::ceiling_divide(block_size, BLOCK_OFFSET_FACTOR as u32) as u64;
+	let mut stats = FragmentationStats::default();
+	stats.present_blocks = offsets.len() as u64;
+	stats.ascending = ascending;
+	if offsets.is_empty()
+	{
+		return stats;
+	}
+
+	stats.runs = 1 + offsets.windows(2).filter(|x| x[1] != x[0] + block_mb).count() as u64;
+	stats.average_run_length = stats.present_blocks as f64 / stats.runs as f64;
+	stats.largest_gap_mb = offsets.windows(2).map(|x| x[1].saturating_sub(x[0] + block_mb)).max().unwrap_or(0);
+
+	return stats;
+}
+
+pub fn count_states<'a, T: PartialEq>(states: &'a [T], block_states: impl Iterator<Item = &'a T>) -> Vec<(&'a T, usize)>
+{
+	let mut counts: Vec<(&T, usize)> = states.iter().map(|x| (x, 0)).collect();
+	for state in block_states
+	{
+		if let Some(count) = counts.iter_mut().find(|x| x.0 == state)
+		{
+			count.1 += 1;
+		}
+	}
+
+	return counts;
+}
+
+pub fn estimate_reclaimable_bytes(payload_blocks: &[PayloadEntry], sector_blocks: &[SectorEntry], block_size: u32, file_length: u64) -> u64
+{
+	// Every allocated block as (file offset, length), the space before the first one is the fixed header and region overhead.
+	// Offsets come straight from the BAT, so a corrupt entry saturates rather than overflowing.
+	let allocated: Vec<(u64, u64)> = payload_blocks.iter()
+		.filter(|x| x.state == PayloadBlockState::FullyPresent || x.state == PayloadBlockState::PartiallyPresent)
+		.map(|x| (x.file_offset_mb.saturating_mul(BLOCK_OFFSET_FACTOR), block_size as u64))
+		.chain(sector_blocks.iter().filter(|x| x.state == SectorBlockState::Present)
+			.map(|x| (x.file_offset_mb.saturating_mul(BLOCK_OFFSET_FACTOR), SECTOR_BITMAP_BLOCK_LEN)))
+		.collect();
+
+	let first_offset = allocated.iter().map(|x| x.0).min();
+	let highest_end = allocated.iter().map(|x| x.0.saturating_add(x.1)).max();
+	if first_offset.is_none() || highest_end.is_none()
+	{
+		return 0;
+	}
+
+	let packed_length = first_offset.unwrap().saturating_add(allocated.iter().map(|x| x.1).sum::<u64>());
+	return highest_end.unwrap().max(file_length).saturating_sub(packed_length);
+}
+
+// The bitmap holds one bit per logical sector of the virtual disk, 2^23 of them whatever the logical sector size is, so
+// decoding it needs no sector size. The caller maps entry n to byte offset n * logical_sector_size within the chunk.
+pub fn read_sector_bitmap(data: &mut (impl Read + Seek), sector_entry: &SectorEntry) -> Fallible<Vec<bool>>
+{
+	ensure!(sector_entry.state == SectorBlockState::Present, "Sector bitmap block at offset {}MiB is not present.", sector_entry.file_offset_mb);
+
+	let mut bitmap_buf: Vec<u8> = vec![0;SECTOR_BITMAP_BLOCK_LEN as usize];
+	read_into(data, sector_entry.file_offset_mb * BLOCK_OFFSET_FACTOR, &mut bitmap_buf)?;
+
+	// Each bit covers one logical sector, starting from the least significant bit of each byte.
+	return Ok(bitmap_buf.iter().flat_map(|x| (0..8).map(move |bit| (x >> bit) & 1 == 1)).collect());
+}
+
+pub fn write_bat_csv(payload_out: &mut impl Write, sector_out: &mut impl Write, entries: impl Iterator<Item = Fallible<BatEntry>>) -> Fallible<()>
+{
+	writeln!(payload_out, "block_index,file_offset,state")?;
+	writeln!(sector_out, "sector_index,file_offset,state")?;
+	for entry in entries
+	{
+		match entry?
+		{
+			BatEntry::Payload(index, entry) => writeln!(payload_out, "{},{},{}", index, entry.file_offset_mb * BLOCK_OFFSET_FACTOR, entry.state)?,
+			BatEntry::Sector(index, entry) => writeln!(sector_out, "{},{},{}", index, entry.file_offset_mb * BLOCK_OFFSET_FACTOR, entry.state)?,
+		}
+	}
+
+	return Ok(());
+}
+
+fn payload_in_file(entry: &PayloadEntry, block_size: u32, file_length: u64) -> bool
+{
+	// Offsets come straight from the BAT, a block whose end does not fit in 64 bits is as far outside the file as any other.
+	let offset = block_file_offset(entry, 0);
+	let end = block_file_offset(entry, block_size as u64);
+	return offset.is_some_and(|x| x >= MIN_PAYLOAD_OFFSET) && end.is_some_and(|x| x <= file_length);
+}
+
+pub fn check_payload_offsets(payload_blocks: &[PayloadEntry], block_size: u32, file_length: u64) -> Fallible<()>
+{
+	let violations: Vec<String> = payload_blocks.iter().enumerate()
+		.filter(|(_, x)| x.state == PayloadBlockState::FullyPresent || x.state == PayloadBlockState::PartiallyPresent)
+		.filter(|(_, x)| !payload_in_file(x, block_size, file_length))
+		.map(|(n, x)| format!("block {} at offset 0x{:X}", n, x.file_offset_mb.saturating_mul(BLOCK_OFFSET_FACTOR)))
+		.collect();
+	ensure!(violations.is_empty(), "Payload blocks lie outside the file of length 0x{:X}: {}.", file_length, violations.join(", "));
+
+	return Ok(());
+}
+
+pub fn read_bat(data: &mut (impl Read + Seek), bat_region: &RegionTableEntry, file_data: &Metadata, file_length: Option<u64>) -> Fallible<(Vec<PayloadEntry>,Vec<SectorEntry>)>
+{
+	return read_bat_with_progress(data, bat_region, file_data, file_length, &mut |_, _| {});
+}
+
+pub fn read_bat_with_progress(data: &mut (impl Read + Seek), bat_region: &RegionTableEntry, file_data: &Metadata, file_length: Option<u64>,
+	progress: &mut dyn FnMut(u64, u64)) -> Fallible<(Vec<PayloadEntry>,Vec<SectorEntry>)>
+{
+	// The space reserved below follows the BAT region length, so that has to be bounded by the file before trusting it.
+	if let Some(file_length) = file_length
+	{
+		check_region_in_file(bat_region, file_length)?;
+	}
+	let mut entries = iterate_bat(data, bat_region, file_data)?;
+	let total_entries = entries.block_values.total_bat_entries;
+	let mut reported_entries: u64 = 0;
+
 	let mut payload_blocks = Vec::<PayloadEntry>::new();
-	payload_blocks.reserve_exact(block_values.payload_blocks as usize);
+	payload_blocks.reserve_exact(entries.block_values.payload_blocks as usize);
 
 	let mut sector_blocks = Vec::<SectorEntry>::new();
-	sector_blocks.reserve_exact(block_values.sector_blocks as usize);
-
-	let entry_count = if has_sectors {block_values.total_bat_entries as usize} else {block_values.payload_blocks as usize};
+	sector_blocks.reserve_exact(entries.block_values.sector_blocks as usize);
 
-	for n in 0..entry_count
+	loop
 	{
-		ensure!(n * BAT_ENTRY_LEN <= bat_region.object_length as usize, "BAT table is longer than recorded in the region table ({} bytes).", bat_region.object_length);
-		if has_sectors && n != 0 && (n % (block_values.chunk_ratio + 1) as usize) == 0
+		match entries.next().transpose()?
 		{
-			sector_blocks.push(SectorEntry::new(data));
+			Some(BatEntry::Payload(_, entry)) => payload_blocks.push(entry),
+			Some(BatEntry::Sector(_, entry)) => sector_blocks.push(entry),
+			None => break,
 		}
-		else
+
+		if entries.entry_index - reported_entries >= BAT_PROGRESS_INTERVAL
 		{
-			payload_blocks.push(PayloadEntry::new(data));
+			reported_entries = entries.entry_index;
+			progress(reported_entries, total_entries);
 		}
 	}
+	progress(total_entries, total_entries);
 
+	if let Some(file_length) = file_length
+	{
+		check_payload_offsets(&payload_blocks, file_data.file_parameters.block_size, file_length)?;
+	}
 	return Ok((payload_blocks, sector_blocks));
 }
 
-pub fn read_bat(data: &mut File, bat_region: &RegionTableEntry, file_data: &Metadata, has_sectors: bool) -> Fallible<(Vec<PayloadEntry>,Vec<SectorEntry>)>
+#[cfg(test)]
+mod tests
 {
-	ensure!(bat_region.region_type == RegionType::BAT, "Passed region data is not for the BAT region.");
+	use std::io::Cursor;
 
-	let block_values = calculate_block_values(file_data)?;
-	let bat_entries = read_bat_table(data, bat_region, &block_values, has_sectors)?;
-	return Ok(bat_entries);
-}
\ No newline at end of file
+	use super::*;
+	use crate::metadata::ParentLocator;
+
+	fn present_block(file_offset_mb: u64) -> PayloadEntry
+	{
+		return PayloadEntry{state: PayloadBlockState::FullyPresent, file_offset_mb, raw: 0};
+	}
+
+	fn metadata_for(virtual_disk_size: u64, block_size: u32, logical_sector_size: u32) -> Metadata
+	{
+		let mut metadata = Metadata::default();
+		metadata.virtual_disk_size = virtual_disk_size;
+		metadata.logical_sector_size = logical_sector_size;
+		metadata.file_parameters.block_size = block_size;
+		return metadata;
+	}
+
+	fn payload_entry(raw: u64) -> Fallible<PayloadEntry>
+	{
+		return PayloadEntry::new(&mut Cursor::new(raw.to_le_bytes()), 0, 0);
+	}
+
+	fn sector_entry(raw: u64) -> Fallible<SectorEntry>
+	{
+		return SectorEntry::new(&mut Cursor::new(raw.to_le_bytes()), 0, 0);
+	}
+
+	#[test]
+	fn payload_entry_states()
+	{
+		for state in PAYLOAD_BLOCK_STATES
+		{
+			let entry = payload_entry((1 << 20) | state as u64).unwrap();
+			assert!(entry.state == state);
+			assert_eq!(entry.file_offset_mb, 1);
+		}
+		for state in [4_u64, 5]
+		{
+			assert!(payload_entry((1 << 20) | state).is_err());
+		}
+	}
+
+	#[test]
+	fn sector_entry_states()
+	{
+		assert!(sector_entry(1 << 20).unwrap().state == SectorBlockState::NotPresent);
+		assert!(sector_entry((1 << 20) | 6).unwrap().state == SectorBlockState::Present);
+		for state in [1_u64, 2, 3, 4, 5, 7]
+		{
+			assert!(sector_entry((1 << 20) | state).is_err());
+		}
+	}
+
+	#[test]
+	fn entry_offset_bits()
+	{
+		// The offset is the top 44 bits in MiB, bits 3 to 19 are reserved and must not leak into either field.
+		let entry = payload_entry(0x0000_0000_0010_0006).unwrap();
+		assert!(entry.state == PayloadBlockState::FullyPresent);
+		assert_eq!(entry.file_offset_mb, 1);
+
+		let entry = payload_entry(0xFFFF_FFFF_FFF0_0007).unwrap();
+		assert!(entry.state == PayloadBlockState::PartiallyPresent);
+		assert_eq!(entry.file_offset_mb, 0x0FFF_FFFF_FFFF);
+
+		let entry = payload_entry(0x8000_0000_000F_FFF8).unwrap();
+		assert!(entry.state == PayloadBlockState::NotPresent);
+		assert_eq!(entry.file_offset_mb, 0x0800_0000_0000);
+
+		let entry = sector_entry(0x0000_1234_5670_0006).unwrap();
+		assert!(entry.state == SectorBlockState::Present);
+		assert_eq!(entry.file_offset_mb, 0x1234567);
+		assert_eq!(entry.raw, 0x0000_1234_5670_0006);
+	}
+
+	#[test]
+	fn short_entry_names_its_offset()
+	{
+		let error = PayloadEntry::new(&mut Cursor::new(vec![0_u8; 4]), 3, 0x300018).err().unwrap();
+		assert!(matches!(error, VhdxError::Read{offset: 0x300018, ..}));
+	}
+
+	#[test]
+	fn four_k_sector_entry_counts()
+	{
+		// 2^23 * 4096 / 32 MiB gives a chunk ratio of 1024, eight times that of a 512 byte sector disk.
+		let mut metadata = metadata_for(40 * u64::pow(1024, 3), 32 * BLOCK_OFFSET_FACTOR as u32, 4096);
+		let dynamic = calculate_block_values(&metadata).unwrap();
+		assert_eq!((dynamic.chunk_ratio, dynamic.payload_blocks, dynamic.sector_blocks, dynamic.total_bat_entries), (1024, 1280, 2, 1281));
+
+		metadata.parent_locator = Some(ParentLocator::default());
+		let differencing = calculate_block_values(&metadata).unwrap();
+		assert_eq!((differencing.chunk_ratio, differencing.payload_blocks, differencing.sector_blocks, differencing.total_bat_entries),
+			(1024, 1280, 2, 2050));
+	}
+
+	#[test]
+	fn four_k_sector_bitmap_interleaving()
+	{
+		// With 256 MiB blocks the chunk ratio is 128, so the sector bitmap entry sits at index 128 between payload blocks 127 and 128.
+		let block_size = 256 * BLOCK_OFFSET_FACTOR as u32;
+		let metadata = metadata_for(130 * block_size as u64, block_size, 4096);
+		let mut bat: Vec<u8> = Vec::new();
+		for n in 0..131_u64
+		{
+			let entry = match n
+			{
+				128 => (0x999 << 20) | SectorBlockState::Present as u64,
+				_ => ((n + 1) << 20) | PayloadBlockState::FullyPresent as u64,
+			};
+			bat.extend_from_slice(&entry.to_le_bytes());
+		}
+		let bat_region = RegionTableEntry{region_type: RegionType::BAT, object_offset: 0, object_length: (131 * BAT_ENTRY_LEN) as u32, ..Default::default()};
+
+		let (payload_blocks, sector_blocks) = read_bat(&mut Cursor::new(bat), &bat_region, &metadata, None).unwrap();
+		assert_eq!(payload_blocks.len(), 130);
+		assert_eq!(sector_blocks.len(), 1);
+		assert_eq!(sector_blocks[0].file_offset_mb, 0x999);
+		assert_eq!(payload_blocks[127].file_offset_mb, 128);
+		assert_eq!(payload_blocks[128].file_offset_mb, 130);
+		assert_eq!(payload_blocks[129].file_offset_mb, 131);
+	}
+
+	#[test]
+	fn bat_region_past_the_end_of_the_file_is_rejected_before_reserving()
+	{
+		// Enough entries to need 12 GB of payload entries, backed by a BAT region far longer than the 3 MiB file.
+		let block_size = 32 * BLOCK_OFFSET_FACTOR as u32;
+		let metadata = metadata_for(532_000_000 * block_size as u64, block_size, 512);
+		let bat_region = RegionTableEntry{region_type: RegionType::BAT, object_offset: BLOCK_OFFSET_FACTOR, object_length: 0xFFF00000, ..Default::default()};
+		let file_length = 3 * BLOCK_OFFSET_FACTOR;
+
+		let result = read_bat(&mut Cursor::new(vec![0_u8; file_length as usize]), &bat_region, &metadata, Some(file_length));
+		assert!(result.unwrap_err().to_string().contains("extends past the end of the file"));
+	}
+
+	#[test]
+	fn virtual_to_physical_survives_huge_offsets()
+	{
+		let block_size = 2 * BLOCK_OFFSET_FACTOR as u32;
+		let metadata = metadata_for(4 * block_size as u64, block_size, 512);
+		let payload_blocks = [present_block(4), present_block(u64::MAX >> 19), PayloadEntry::default()];
+
+		assert_eq!(virtual_to_physical(&metadata, &payload_blocks, 0x10), Some(4 * BLOCK_OFFSET_FACTOR + 0x10));
+		assert_eq!(virtual_to_physical(&metadata, &payload_blocks, block_size as u64 + 0x10), None);
+		assert_eq!(virtual_to_physical(&metadata, &payload_blocks, 2 * block_size as u64), None);
+	}
+
+	#[test]
+	fn dynamic_and_differencing_entry_counts()
+	{
+		// A 40 GiB disk with 32 MiB blocks and 512 byte sectors, as created by Hyper-V with the default settings.
+		let mut metadata = metadata_for(40 * u64::pow(1024, 3), 32 * BLOCK_OFFSET_FACTOR as u32, 512);
+		let dynamic = calculate_block_values(&metadata).unwrap();
+		assert_eq!(dynamic, FileBlockValues{chunk_ratio: 128, payload_blocks: 1280, sector_blocks: 10, total_bat_entries: 1289});
+
+		metadata.parent_locator = Some(ParentLocator::default());
+		let differencing = calculate_block_values(&metadata).unwrap();
+		assert_eq!(differencing, FileBlockValues{chunk_ratio: 128, payload_blocks: 1280, sector_blocks: 10, total_bat_entries: 1290});
+	}
+
+	#[test]
+	fn chunk_ratio_must_be_a_power_of_two()
+	{
+		// 2^23 * 512 / 3 MiB is 1365, which would misplace every sector bitmap entry.
+		let metadata = metadata_for(64 * BLOCK_OFFSET_FACTOR, 3 * BLOCK_OFFSET_FACTOR as u32, 512);
+		let error = calculate_block_values(&metadata).unwrap_err();
+		assert!(error.to_string().starts_with("Chunk ratio 0x555 "));
+
+		let metadata = metadata_for(64 * BLOCK_OFFSET_FACTOR, 3 * BLOCK_OFFSET_FACTOR as u32, 4096);
+		assert!(calculate_block_values(&metadata).is_err());
+		let metadata = metadata_for(64 * BLOCK_OFFSET_FACTOR, 4 * BLOCK_OFFSET_FACTOR as u32, 512);
+		assert_eq!(calculate_block_values(&metadata).unwrap().chunk_ratio, 1024);
+	}
+
+	#[test]
+	fn empty_disk_has_no_bat_entries()
+	{
+		let metadata = metadata_for(0, 32 * BLOCK_OFFSET_FACTOR as u32, 512);
+		let block_values = calculate_block_values(&metadata).unwrap();
+		assert_eq!(block_values.payload_blocks, 0);
+		assert_eq!(block_values.total_bat_entries, 0);
+	}
+
+	#[test]
+	fn payload_offsets_outside_the_file_are_named()
+	{
+		let block_size = 2 * BLOCK_OFFSET_FACTOR as u32;
+		let file_length = 8 * BLOCK_OFFSET_FACTOR;
+		check_payload_offsets(&[present_block(4), present_block(6), PayloadEntry::default()], block_size, file_length).unwrap();
+
+		let error = check_payload_offsets(&[present_block(4), present_block(0), present_block(7)], block_size, file_length).unwrap_err();
+		assert_eq!(error.to_string(), "Payload blocks lie outside the file of length 0x800000: block 1 at offset 0x0, block 2 at offset 0x700000.");
+	}
+
+	#[test]
+	fn payload_offset_overflow_is_a_violation()
+	{
+		let payload_blocks = [present_block(4), present_block(BAT_ENTRY_OFFSET_MASK >> 20), present_block(u64::MAX)];
+		let error = check_payload_offsets(&payload_blocks, 2 * BLOCK_OFFSET_FACTOR as u32, 8 * BLOCK_OFFSET_FACTOR).unwrap_err();
+
+		assert!(error.to_string().ends_with("block 1 at offset 0xFFFFFFFFFFF00000, block 2 at offset 0xFFFFFFFFFFFFFFFF."));
+	}
+
+	#[test]
+	fn fragmentation_runs_and_gaps()
+	{
+		let payload_blocks = [present_block(8), present_block(10), present_block(4), PayloadEntry::default(), present_block(6)];
+		let stats = fragmentation_stats(&payload_blocks, 2 * BLOCK_OFFSET_FACTOR as u32);
+
+		assert_eq!(stats, FragmentationStats{present_blocks: 4, runs: 1, average_run_length: 4.0, largest_gap_mb: 0, ascending: false});
+
+		let payload_blocks = [present_block(4), present_block(6), present_block(12)];
+		let stats = fragmentation_stats(&payload_blocks, 2 * BLOCK_OFFSET_FACTOR as u32);
+		assert_eq!(stats, FragmentationStats{present_blocks: 3, runs: 2, average_run_length: 1.5, largest_gap_mb: 4, ascending: true});
+	}
+
+	#[test]
+	fn fragmentation_at_the_largest_bat_offset()
+	{
+		// A BAT entry holds a 44-bit MiB offset, so the last block a parsed file can describe ends at 2^44 MiB.
+		let last_mb = BAT_ENTRY_OFFSET_MASK >> 20;
+		let payload_blocks = [present_block(4), present_block(last_mb - 2), present_block(last_mb)];
+		let stats = fragmentation_stats(&payload_blocks, 2 * BLOCK_OFFSET_FACTOR as u32);
+
+		assert_eq!(stats.runs, 2);
+		assert_eq!(stats.largest_gap_mb, last_mb - 2 - 6);
+	}
+
+	#[test]
+	fn reclaimable_bytes_counts_gaps()
+	{
+		let payload_blocks = [present_block(4), present_block(8), PayloadEntry::default()];
+		let block_size = 2 * BLOCK_OFFSET_FACTOR as u32;
+
+		// Blocks at 4 MiB and 8 MiB could be packed into 4 MiB to 8 MiB, leaving the 2 MiB gap and the 2 MiB tail.
+		assert_eq!(estimate_reclaimable_bytes(&payload_blocks, &[], block_size, 12 * BLOCK_OFFSET_FACTOR), 4 * BLOCK_OFFSET_FACTOR);
+		assert_eq!(estimate_reclaimable_bytes(&[PayloadEntry::default()], &[], block_size, 12 * BLOCK_OFFSET_FACTOR), 0);
+	}
+
+	#[test]
+	fn reclaimable_bytes_survives_huge_offsets()
+	{
+		let payload_blocks = [present_block(4), present_block(BAT_ENTRY_OFFSET_MASK >> 20)];
+		let reclaimable = estimate_reclaimable_bytes(&payload_blocks, &[], 2 * BLOCK_OFFSET_FACTOR as u32, 12 * BLOCK_OFFSET_FACTOR);
+
+		assert_eq!(reclaimable, u64::MAX - (4 * BLOCK_OFFSET_FACTOR) - (4 * BLOCK_OFFSET_FACTOR));
+	}
+}
@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::{
+	collections::HashSet,
+	ffi::OsStr,
+	fmt,
+	path::{self, Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use crate::error::{Fallible,VhdxError};
+use crate::{ensure,format_err};
+use crate::metadata::{ParentLinkageMatch,ParentLocator,ParentLocatorType};
+use crate::VhdxFile;
+
+pub const DEFAULT_MAX_CHAIN_DEPTH: u64 = 100;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ParentPathSource
+{
+	RelativePath,
+	VolumePath,
+	AbsolutePath,
+}
+
+impl fmt::Display for ParentPathSource {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ParentPathSource::RelativePath => write!(f, "relative path"),
+			ParentPathSource::VolumePath => write!(f, "volume path"),
+			ParentPathSource::AbsolutePath => write!(f, "absolute path"),
+		}
+	}
+}
+
+impl ParentPathSource
+{
+	pub fn locator_value<'a>(self: &Self, locator: &'a ParentLocator) -> &'a str
+	{
+		match *self
+		{
+			ParentPathSource::RelativePath => return &locator.relative_path,
+			ParentPathSource::VolumePath => return &locator.volume_path,
+			ParentPathSource::AbsolutePath => return &locator.absolute_win32_path,
+		}
+	}
+}
+
+pub struct ChainLink
+{
+	pub link: usize,
+	pub child_path: PathBuf,
+	pub parent_path: PathBuf,
+	pub source: ParentPathSource,
+	pub linkage: ParentLinkageMatch,
+}
+
+fn normalise_relative_path(relative_path: &str) -> PathBuf
+{
+	// Locators are written on Windows, so the separators are backslashes whatever platform the chain is read on.
+	let relative_path = relative_path.replace('\\', path::MAIN_SEPARATOR_STR);
+	let current_dir = format!(".{}", path::MAIN_SEPARATOR);
+	return PathBuf::from(relative_path.strip_prefix(&current_dir).unwrap_or(&relative_path));
+}
+
+pub fn calc_parent_path(locator: &ParentLocator, child_path: &Path) -> Fallible<(String, ParentPathSource)>
+{
+	// A bare file name has an empty parent, so only a root or empty path has nothing to resolve against.
+	let child_dir = child_path.parent().ok_or_else(|| format_err!("Cannot resolve a parent relative to {}.", child_path.display()))?;
+	let relative_path = child_dir.join(normalise_relative_path(&locator.relative_path));
+	if !locator.relative_path.is_empty() && relative_path.exists()
+	{
+		let relative_path = relative_path.canonicalize()?;
+		let relative_path = relative_path.to_str().ok_or_else(|| format_err!("Parent path {} is not valid UTF-8.", relative_path.display()))?;
+		return Ok((relative_path.to_string(), ParentPathSource::RelativePath));
+	}
+	else if !locator.volume_path.is_empty() &&
+		Path::new(OsStr::new(locator.volume_path.as_str())).exists()
+	{
+		return Ok((locator.volume_path.clone(), ParentPathSource::VolumePath));
+	}
+	else if !locator.absolute_win32_path.is_empty() &&
+		Path::new(OsStr::new(locator.absolute_win32_path.as_str())).exists()
+	{
+		return Ok((locator.absolute_win32_path.clone(), ParentPathSource::AbsolutePath));
+	}
+	else
+	{
+		return Err(format_err!("Could not find parent with any of the relative path '{}', the volume path '{}' or the absolute path '{}'.",
+			locator.relative_path, locator.volume_path, locator.absolute_win32_path));
+	}
+}
+
+pub fn check_parent_linkage(locator: &ParentLocator, parent_path: &Path, data_write_id: Uuid) -> Fallible<ParentLinkageMatch>
+{
+	let linkage = locator.matches_parent(data_write_id);
+	ensure!(linkage != ParentLinkageMatch::None, "Parent disk located at {} has Data Write GUID {} but metadata expected an ID of either {} or {}.",
+		parent_path.display(), data_write_id, locator.parent_linkage, locator.parent_linkage2);
+
+	return Ok(linkage);
+}
+
+fn link_error(link: usize, child_path: &Path, error: VhdxError) -> VhdxError
+{
+	return format_err!("Link {} of the parent chain, from {} to its parent, failed: {}", link, child_path.display(), error);
+}
+
+pub fn resolve_parent_chain<P: AsRef<Path>>(path: P) -> Fallible<Vec<VhdxFile>>
+{
+	return resolve_parent_chain_with_depth(path, DEFAULT_MAX_CHAIN_DEPTH);
+}
+
+pub fn resolve_parent_chain_with_depth<P: AsRef<Path>>(path: P, max_depth: u64) -> Fallible<Vec<VhdxFile>>
+{
+	return resolve_parent_chain_with(path, max_depth, |_| {});
+}
+
+// Each link is passed to on_link once it has been checked, so a caller can report progress before a later link fails.
+pub fn resolve_parent_chain_with<P: AsRef<Path>>(path: P, max_depth: u64, mut on_link: impl FnMut(&ChainLink)) -> Fallible<Vec<VhdxFile>>
+{
+	let mut chain = vec![VhdxFile::open(&path)?];
+	let mut visited_ids = HashSet::<Uuid>::from([chain[0].vhdx_header.data_write_id]);
+	let mut child_path = path.as_ref().to_path_buf();
+
+	// Each link is numbered by the position of its child, starting from 1 for the file that was opened.
+	while let Some(locator) = chain.last().unwrap().metadata.parent_locator.clone()
+	{
+		let link = chain.len();
+		ensure!((link as u64) < max_depth, "Parent chain is longer than the maximum depth of {}.", max_depth);
+		ensure!(locator.locator_type == ParentLocatorType::Vhdx,
+			"Link {} of the parent chain, from {} to its parent, uses an unknown parent locator type.", link, child_path.display());
+
+		let (parent_path, source) = calc_parent_path(&locator, &child_path).map_err(|error| link_error(link, &child_path, error))?;
+		let parent_path = PathBuf::from(parent_path);
+		let parent = VhdxFile::open(&parent_path).map_err(|error| link_error(link, &child_path, error))?;
+		let data_write_id = parent.vhdx_header.data_write_id;
+		let linkage = check_parent_linkage(&locator, &parent_path, data_write_id).map_err(|error| link_error(link, &child_path, error))?;
+		ensure!(visited_ids.insert(data_write_id), "Parent chain contains a cycle at {}.", parent_path.display());
+
+		let chain_link = ChainLink{link, child_path, parent_path, source, linkage};
+		on_link(&chain_link);
+		chain.push(parent);
+		child_path = chain_link.parent_path;
+	}
+
+	return Ok(chain);
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::fs;
+
+	use super::*;
+
+	fn locator_with_relative_path(relative_path: &str) -> ParentLocator
+	{
+		let mut locator = ParentLocator::default();
+		locator.relative_path = String::from(relative_path);
+		return locator;
+	}
+
+	#[test]
+	fn bare_child_name_resolves_against_the_current_directory()
+	{
+		let locator = locator_with_relative_path(".\\Cargo.toml");
+		let (parent_path, source) = calc_parent_path(&locator, Path::new("child.vhdx")).unwrap();
+
+		assert_eq!(source, ParentPathSource::RelativePath);
+		assert_eq!(PathBuf::from(parent_path), Path::new("Cargo.toml").canonicalize().unwrap());
+	}
+
+	#[test]
+	fn root_child_path_is_an_error()
+	{
+		let locator = locator_with_relative_path("parent.vhdx");
+
+		assert!(calc_parent_path(&locator, Path::new("/")).is_err());
+		assert!(calc_parent_path(&locator, Path::new("")).is_err());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn non_utf8_parent_path_is_an_error()
+	{
+		use std::os::unix::ffi::OsStrExt;
+
+		let dir = std::env::temp_dir().join(OsStr::from_bytes(b"vhdx_inspector_chain_\xFF"));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("parent.vhdx"), b"").unwrap();
+
+		let result = calc_parent_path(&locator_with_relative_path("parent.vhdx"), &dir.join("child.vhdx"));
+		fs::remove_dir_all(&dir).unwrap();
+		assert!(result.unwrap_err().to_string().ends_with("is not valid UTF-8."));
+	}
+}
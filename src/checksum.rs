@@ -1,16 +1,60 @@
 // SPDX-License-Identifier: MIT
 // Copyright (c) Nick Moss.
 
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use byteorder::{LittleEndian,ByteOrder,WriteBytesExt};
 use crc32c::crc32c;
-use failure::{ensure,Fallible};
+
+use crate::error::{Fallible,VhdxError};
+use crate::ensure;
+use crate::reader::read_into;
 
 pub const CHECKSUM_LENGTH: usize = 0x4;
 
-pub fn check_checksum(mut data: Vec<u8>, offset: usize, expected: u32, type_name: &str) -> Fallible<()>
+pub struct ChecksumFix
+{
+	pub type_name: String,
+	pub offset: usize,
+	pub checksum_offset: usize,
+	pub old: u32,
+	pub new: u32,
+}
+
+pub fn calculate_checksum(mut data: Vec<u8>, offset: usize) -> u32
+{
+	data.splice(offset..(offset + CHECKSUM_LENGTH), [0_u8;CHECKSUM_LENGTH]);
+	return crc32c(&data);
+}
+
+pub fn check_checksum(data: Vec<u8>, offset: usize, expected: u32, type_name: &str) -> Fallible<u32>
+{
+	let check = calculate_checksum(data, offset);
+
+	if expected != check
+	{
+		return Err(VhdxError::ChecksumMismatch{type_name: String::from(type_name), expected, computed: check});
+	}
+	return Ok(check);
+}
+
+pub fn recompute_checksum(data: &mut (impl Read + Seek), offset: usize, length: usize, signature: &[u8], type_name: &str) -> Fallible<ChecksumFix>
+{
+	let mut buffer: Vec<u8> = vec![0;length];
+	read_into(data, offset as u64, &mut buffer)?;
+	ensure!(buffer.starts_with(signature), BadSignature, "{} signature at 0x{:X} is invalid.", type_name, offset);
+
+	let checksum_offset = signature.len();
+	let old = LittleEndian::read_u32(&buffer[checksum_offset..]);
+	let new = calculate_checksum(buffer, checksum_offset);
+
+	return Ok(ChecksumFix{type_name: String::from(type_name), offset, checksum_offset: offset + checksum_offset, old, new});
+}
+
+pub fn write_checksum(data: &mut (impl Write + Seek), fix: &ChecksumFix) -> Fallible<()>
 {
-	data.splice(offset..(offset + CHECKSUM_LENGTH), [0 as u8;CHECKSUM_LENGTH]);
-	let check = crc32c(&data);
+	data.seek(SeekFrom::Start(fix.checksum_offset as u64))?;
+	data.write_u32::<LittleEndian>(fix.new)?;
 
-	ensure!(expected == check, format!("{} signature is invalid.", type_name));
 	return Ok(());
-}
\ No newline at end of file
+}
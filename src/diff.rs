@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::io::{Read, Seek};
+
+use crate::error::Fallible;
+use crate::block::{PayloadBlockState,PayloadEntry};
+use crate::reader::read_into;
+use crate::VhdxFile;
+
+const BLOCK_OFFSET_FACTOR: u64 = u64::pow(1024, 2);
+
+pub struct ChangedBlock
+{
+	pub index: u64,
+	pub state_or_offset_changed: bool,
+	pub data_changed: bool,
+}
+
+pub struct FieldDifference
+{
+	pub field: &'static str,
+	pub first: String,
+	pub second: String,
+}
+
+fn read_payload_block(data: &mut (impl Read + Seek), entry: &PayloadEntry, block_size: u32) -> Fallible<Vec<u8>>
+{
+	let mut buffer: Vec<u8> = vec![0;block_size as usize];
+	read_into(data, entry.file_offset_mb * BLOCK_OFFSET_FACTOR, &mut buffer)?;
+	return Ok(buffer);
+}
+
+pub fn diff_payload_blocks(first_file: &mut (impl Read + Seek), first_blocks: &[PayloadEntry],
+	second_file: &mut (impl Read + Seek), second_blocks: &[PayloadEntry], block_size: u32, compare_data: bool) -> Fallible<Vec<ChangedBlock>>
+{
+	let mut changed = Vec::<ChangedBlock>::new();
+
+	for (index, (first, second)) in first_blocks.iter().zip(second_blocks.iter()).enumerate()
+	{
+		let state_or_offset_changed = first.state != second.state || first.file_offset_mb != second.file_offset_mb;
+		let mut data_changed = false;
+
+		if compare_data && first.state == PayloadBlockState::FullyPresent && second.state == PayloadBlockState::FullyPresent
+		{
+			data_changed = read_payload_block(first_file, first, block_size)? != read_payload_block(second_file, second, block_size)?;
+		}
+
+		if state_or_offset_changed || data_changed
+		{
+			changed.push(ChangedBlock{index: index as u64, state_or_offset_changed, data_changed});
+		}
+	}
+
+	return Ok(changed);
+}
+
+pub fn diff_fields<R, S>(first: &VhdxFile<R>, second: &VhdxFile<S>) -> Vec<FieldDifference>
+{
+	let fields: [(&'static str, String, String); 9] = [
+		("Block size", format!("0x{:X}", first.metadata.file_parameters.block_size), format!("0x{:X}", second.metadata.file_parameters.block_size)),
+		("Virtual disk size", format!("0x{:X}", first.metadata.virtual_disk_size), format!("0x{:X}", second.metadata.virtual_disk_size)),
+		("Logical sector size", format!("0x{:X}", first.metadata.logical_sector_size), format!("0x{:X}", second.metadata.logical_sector_size)),
+		("Physical sector size", format!("0x{:X}", first.metadata.physical_sector_size), format!("0x{:X}", second.metadata.physical_sector_size)),
+		("Has parent", first.metadata.file_parameters.has_parent.to_string(), second.metadata.file_parameters.has_parent.to_string()),
+		("Leave block allocated", first.metadata.file_parameters.leave_block_allocated.to_string(),
+			second.metadata.file_parameters.leave_block_allocated.to_string()),
+		("Virtual disk ID", first.metadata.virtual_disk_id.to_string(), second.metadata.virtual_disk_id.to_string()),
+		("File Write GUID", first.vhdx_header.file_write_id.to_string(), second.vhdx_header.file_write_id.to_string()),
+		("Data Write GUID", first.vhdx_header.data_write_id.to_string(), second.vhdx_header.data_write_id.to_string()),
+	];
+
+	return fields.into_iter().filter(|(_, x, y)| x != y).map(|(field, first, second)| FieldDifference{field, first, second}).collect();
+}
+
+pub fn count_state_differences(first_blocks: &[PayloadEntry], second_blocks: &[PayloadEntry]) -> usize
+{
+	// Blocks beyond the end of the shorter table count as differing.
+	let differing = first_blocks.iter().zip(second_blocks.iter()).filter(|(x, y)| x.state != y.state).count();
+	return differing + first_blocks.len().abs_diff(second_blocks.len());
+}
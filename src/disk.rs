@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::{
+	fs::File,
+	io::{Read, Seek},
+	path::Path,
+};
+
+use crate::error::Fallible;
+use crate::block::PayloadBlockState;
+use crate::vhd_footer::{self,VhdFooter};
+use crate::{classify_disk_type,VhdType,VhdxFile};
+
+pub trait VirtualDisk
+{
+	fn format_name(self: &Self) -> &'static str;
+	fn virtual_size(self: &Self) -> u64;
+	fn block_size(self: &Self) -> Option<u32>;
+	fn disk_type(self: &Self) -> VhdType;
+	fn block_states(self: &Self) -> Vec<PayloadBlockState>;
+}
+
+pub struct VhdFile<R = File>
+{
+	pub file: R,
+	pub footer_offset: u64,
+	pub footer: VhdFooter,
+}
+
+impl VhdFile
+{
+	pub fn open<P: AsRef<Path>>(path: P) -> Fallible<VhdFile>
+	{
+		return VhdFile::from_reader(File::open(path)?);
+	}
+}
+
+impl<R: Read + Seek> VhdFile<R>
+{
+	pub fn from_reader(mut reader: R) -> Fallible<VhdFile<R>>
+	{
+		let (footer_offset, footer) = vhd_footer::read_vhd_footer(&mut reader)?;
+		return Ok(VhdFile{file: reader, footer_offset, footer});
+	}
+}
+
+// Only the footer of a VHD is parsed, so it has no block information to report.
+impl<R> VirtualDisk for VhdFile<R>
+{
+	fn format_name(self: &Self) -> &'static str
+	{
+		return "VHD";
+	}
+
+	fn virtual_size(self: &Self) -> u64
+	{
+		return self.footer.current_size;
+	}
+
+	fn block_size(self: &Self) -> Option<u32>
+	{
+		return None;
+	}
+
+	fn disk_type(self: &Self) -> VhdType
+	{
+		return self.footer.disk_type;
+	}
+
+	fn block_states(self: &Self) -> Vec<PayloadBlockState>
+	{
+		return Vec::new();
+	}
+}
+
+impl<R> VirtualDisk for VhdxFile<R>
+{
+	fn format_name(self: &Self) -> &'static str
+	{
+		return "VHDX";
+	}
+
+	fn virtual_size(self: &Self) -> u64
+	{
+		return self.metadata.virtual_disk_size;
+	}
+
+	fn block_size(self: &Self) -> Option<u32>
+	{
+		return Some(self.metadata.file_parameters.block_size);
+	}
+
+	fn disk_type(self: &Self) -> VhdType
+	{
+		// A file opened without its BAT has no payload blocks, the type then comes from the metadata alone.
+		let payload_blocks = Some(self.payload_blocks.as_slice()).filter(|x| !x.is_empty());
+		return classify_disk_type(&self.metadata, payload_blocks);
+	}
+
+	fn block_states(self: &Self) -> Vec<PayloadBlockState>
+	{
+		return self.payload_blocks.iter().map(|x| x.state).collect();
+	}
+}
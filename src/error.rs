@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::{
+	io,
+	string::{FromUtf8Error,FromUtf16Error},
+};
+
+use thiserror::Error;
+
+pub const EXIT_OTHER: i32 = 1;
+pub const EXIT_BAD_SIGNATURE: i32 = 2;
+pub const EXIT_CHECKSUM_MISMATCH: i32 = 3;
+pub const EXIT_INVALID_METADATA: i32 = 4;
+pub const EXIT_IO: i32 = 5;
+
+#[derive(Debug, Error)]
+pub enum VhdxError
+{
+	#[error("{0}")]
+	BadSignature(String),
+	#[error("{type_name} checksum mismatch: expected 0x{expected:08X}, computed 0x{computed:08X}.")]
+	ChecksumMismatch{type_name: String, expected: u32, computed: u32},
+	#[error("{0}")]
+	InvalidMetadata(String),
+	#[error("Both {structure} are invalid. 0x{first_offset:X}: {first} 0x{second_offset:X}: {second}")]
+	BothCopiesInvalid{structure: String, first_offset: usize, first: Box<VhdxError>, second_offset: usize, second: Box<VhdxError>},
+	#[error("Failed to read {field} at file offset 0x{offset:X}: {source}")]
+	Read{field: String, offset: u64, source: Box<VhdxError>},
+	#[error("{0}")]
+	Invalid(String),
+	#[error(transparent)]
+	Io(#[from] io::Error),
+	#[error(transparent)]
+	Uuid(#[from] uuid::Error),
+	#[error(transparent)]
+	Utf8(#[from] FromUtf8Error),
+	#[error(transparent)]
+	Utf16(#[from] FromUtf16Error),
+	#[error(transparent)]
+	Json(#[from] serde_json::Error),
+	#[error(transparent)]
+	Yaml(#[from] serde_yaml::Error),
+}
+
+impl VhdxError
+{
+	// The offset is only worked out by the caller once a read has failed, so reading a field costs no extra seek.
+	pub fn read(field: &str, offset: u64, error: VhdxError) -> VhdxError
+	{
+		return VhdxError::Read{field: String::from(field), offset, source: Box::new(error)};
+	}
+
+	pub fn kind(self: &Self) -> &'static str
+	{
+		match self
+		{
+			VhdxError::BadSignature(_) => return "BadSignature",
+			VhdxError::ChecksumMismatch{..} => return "ChecksumMismatch",
+			VhdxError::InvalidMetadata(_) => return "InvalidMetadata",
+			VhdxError::BothCopiesInvalid{..} => return "BothCopiesInvalid",
+			VhdxError::Read{..} => return "Read",
+			VhdxError::Invalid(_) => return "Invalid",
+			VhdxError::Io(_) => return "Io",
+			VhdxError::Uuid(_) => return "Uuid",
+			VhdxError::Utf8(_) => return "Utf8",
+			VhdxError::Utf16(_) => return "Utf16",
+			VhdxError::Json(_) => return "Json",
+			VhdxError::Yaml(_) => return "Yaml",
+		}
+	}
+
+	// Only errors raised while reading a particular structure know where in the file they happened.
+	pub fn offset(self: &Self) -> Option<u64>
+	{
+		match self
+		{
+			VhdxError::Read{offset, ..} => return Some(*offset),
+			VhdxError::BothCopiesInvalid{first_offset, ..} => return Some(*first_offset as u64),
+			_ => return None,
+		}
+	}
+
+	pub fn exit_code(self: &Self) -> i32
+	{
+		match self
+		{
+			VhdxError::BadSignature(_) => return EXIT_BAD_SIGNATURE,
+			VhdxError::ChecksumMismatch{..} => return EXIT_CHECKSUM_MISMATCH,
+			VhdxError::InvalidMetadata(_) => return EXIT_INVALID_METADATA,
+			VhdxError::Io(_) => return EXIT_IO,
+			VhdxError::Read{source, ..} => return source.exit_code(),
+			// When both copies fail for the same reason, report that reason.
+			VhdxError::BothCopiesInvalid{first, second, ..} if first.exit_code() == second.exit_code() => return first.exit_code(),
+			_ => return EXIT_OTHER,
+		}
+	}
+}
+
+pub type Fallible<T> = Result<T, VhdxError>;
+
+#[macro_export]
+macro_rules! format_err {
+	($variant:ident, $($arg:tt)+) => {
+		$crate::error::VhdxError::$variant(format!($($arg)+))
+	};
+	($($arg:tt)+) => {
+		$crate::error::VhdxError::Invalid(format!($($arg)+))
+	};
+}
+
+#[macro_export]
+macro_rules! ensure {
+	($cond:expr, $($arg:tt)+) => {
+		if !($cond)
+		{
+			return Err($crate::format_err!($($arg)+));
+		}
+	};
+}
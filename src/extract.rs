@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::{
+	fs::File,
+	io::{Read, Seek, SeekFrom, Write},
+};
+
+use crate::error::Fallible;
+use crate::ensure;
+use crate::block::{PayloadBlockState,PayloadEntry};
+use crate::io::read_virtual_range;
+use crate::metadata::Metadata;
+use crate::reader::read_into;
+use crate::VhdxFile;
+
+const BLOCK_OFFSET_FACTOR: u64 = u64::pow(1024, 2);
+
+pub fn extract_raw(vhdx: &mut (impl Read + Seek), metadata: &Metadata, payload_blocks: &[PayloadEntry], out: &mut File) -> Fallible<()>
+{
+	ensure!(metadata.parent_locator.is_none(), "Cannot extract a differencing disk without reading its parent.");
+
+	let block_size = metadata.file_parameters.block_size as u64;
+	let virtual_disk_size = metadata.virtual_disk_size;
+	let mut buffer: Vec<u8> = vec![0;block_size as usize];
+	out.set_len(0)?;
+
+	for (index, entry) in payload_blocks.iter().enumerate()
+	{
+		let block_offset = index as u64 * block_size;
+		if block_offset >= virtual_disk_size
+		{
+			break;
+		}
+
+		match entry.state
+		{
+			PayloadBlockState::FullyPresent | PayloadBlockState::PartiallyPresent =>
+			{
+				let length = u64::min(block_size, virtual_disk_size - block_offset) as usize;
+				read_into(vhdx, entry.file_offset_mb * BLOCK_OFFSET_FACTOR, &mut buffer[..length])?;
+				out.seek(SeekFrom::Start(block_offset))?;
+				out.write_all(&buffer[..length])?;
+			},
+			// Blocks without data are left as holes in the emptied output, which read back as zeros once it is sized below.
+			_ => {},
+		}
+	}
+
+	out.set_len(virtual_disk_size)?;
+
+	return Ok(());
+}
+
+pub fn flatten_chain<R: Read + Seek>(chain: &mut [VhdxFile<R>], out: &mut File) -> Fallible<()>
+{
+	ensure!(!chain.is_empty(), "No VHDX file was given to flatten.");
+
+	let sector_size = chain[0].metadata.logical_sector_size as u64;
+	let block_size = chain[0].metadata.file_parameters.block_size as u64;
+	let virtual_disk_size = chain[0].metadata.virtual_disk_size;
+	out.set_len(0)?;
+
+	// Each block is read from the first disk in the chain that has it, sectors of partially present blocks are resolved through the bitmaps.
+	let mut block_offset: u64 = 0;
+	while block_offset < virtual_disk_size
+	{
+		let length = u64::min(block_size, virtual_disk_size - block_offset);
+		let buffer = read_virtual_range(chain, block_offset / sector_size, length / sector_size)?;
+		if buffer.iter().any(|x| *x != 0)
+		{
+			out.seek(SeekFrom::Start(block_offset))?;
+			out.write_all(&buffer)?;
+		}
+		block_offset += length;
+	}
+
+	out.set_len(virtual_disk_size)?;
+
+	return Ok(());
+}
@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+// Every pointer passed in must be null or valid for the duration of the call, and handles must come from vhdx_open.
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+	cell::RefCell,
+	ffi::{c_char, c_int, CStr, CString},
+	panic::{self, AssertUnwindSafe},
+	ptr,
+};
+
+use crate::error::{Fallible,EXIT_OTHER};
+use crate::format_err;
+use crate::VhdxFile;
+
+pub const VHDX_OK: c_int = 0;
+
+pub struct VhdxHandle
+{
+	vhdx: VhdxFile,
+}
+
+#[repr(C)]
+pub struct VhdxErrorInfo
+{
+	pub code: c_int,
+	pub message: *const c_char,
+}
+
+thread_local! {
+	// The message handed out is borrowed from here and stays valid until the next failing call on the same thread.
+	static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(error_info: *mut VhdxErrorInfo, code: c_int, message: String)
+{
+	if error_info.is_null()
+	{
+		return;
+	}
+
+	let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+	LAST_ERROR.with(|last_error| {
+		let mut last_error = last_error.borrow_mut();
+		*last_error = message;
+		unsafe { *error_info = VhdxErrorInfo{code, message: last_error.as_ptr()} };
+	});
+}
+
+fn open_path(path: *const c_char) -> Fallible<VhdxFile>
+{
+	if path.is_null()
+	{
+		return Err(format_err!("No path was given."));
+	}
+
+	let path = unsafe { CStr::from_ptr(path) }.to_str().map_err(|error| format_err!("Path is not valid UTF-8: {}", error))?;
+	return VhdxFile::open(path);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vhdx_open(path: *const c_char, error_info: *mut VhdxErrorInfo) -> *mut VhdxHandle
+{
+	// A panic must not unwind into the caller, so it is reported like any other failure.
+	match panic::catch_unwind(AssertUnwindSafe(|| open_path(path)))
+	{
+		Ok(Ok(vhdx)) =>
+		{
+			if !error_info.is_null()
+			{
+				*error_info = VhdxErrorInfo{code: VHDX_OK, message: ptr::null()};
+			}
+			return Box::into_raw(Box::new(VhdxHandle{vhdx}));
+		},
+		Ok(Err(error)) => set_error(error_info, error.exit_code(), error.to_string()),
+		Err(_) => set_error(error_info, EXIT_OTHER, String::from("Parsing the file panicked.")),
+	}
+
+	return ptr::null_mut();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vhdx_free(handle: *mut VhdxHandle)
+{
+	if !handle.is_null()
+	{
+		drop(Box::from_raw(handle));
+	}
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vhdx_virtual_size(handle: *const VhdxHandle) -> u64
+{
+	return handle.as_ref().map_or(0, |x| x.vhdx.metadata.virtual_disk_size);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vhdx_logical_sector_size(handle: *const VhdxHandle) -> u32
+{
+	return handle.as_ref().map_or(0, |x| x.vhdx.metadata.logical_sector_size);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vhdx_physical_sector_size(handle: *const VhdxHandle) -> u32
+{
+	return handle.as_ref().map_or(0, |x| x.vhdx.metadata.physical_sector_size);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vhdx_block_count(handle: *const VhdxHandle) -> u64
+{
+	return handle.as_ref().map_or(0, |x| x.vhdx.payload_blocks.len() as u64);
+}
@@ -1,33 +1,40 @@
 // SPDX-License-Identifier: MIT
 // Copyright (c) Nick Moss.
 
-use std::{
-	fs::File,
-	io::{Seek, SeekFrom},
-};
-
-use failure::{ensure,Fallible};
+use std::io::{Seek, Read, SeekFrom};
 
+use crate::error::Fallible;
+use crate::ensure;
 use crate::reader::ReadValue;
 
-const FILE_HEADER_OFFSET: usize = 0x0;
-const FILE_HEADER_SIG: [u8; FILE_HEADER_SIG_LEN] = [0x76, 0x68, 0x64, 0x78, 0x66, 0x69, 0x6c, 0x65];
+pub const FILE_HEADER_OFFSET: usize = 0x0;
+pub const FILE_HEADER_SIG: [u8; FILE_HEADER_SIG_LEN] = [0x76, 0x68, 0x64, 0x78, 0x66, 0x69, 0x6c, 0x65];
 const FILE_HEADER_SIG_LEN: usize = 0x8;
-const FILE_HEADER_CREATOR_LEN: usize = 0x200;
+pub const FILE_HEADER_CREATOR_LEN: usize = 0x200;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct Header
 {
-	pub creator: String
+	pub creator: String,
+	pub creator_length: usize,
 }
 
 fn check_file_header_valid(signature: &[u8]) -> Fallible<()>
 {
-	ensure!(signature == FILE_HEADER_SIG, "File signature is invalid.");
+	ensure!(signature == FILE_HEADER_SIG, BadSignature, "File signature is invalid.");
 	return Ok(());
 }
 
-pub fn read_file_header(data: &mut File) -> Fallible<Header>
+pub fn is_vhdx_file(data: &mut (impl Read + Seek)) -> Fallible<bool>
+{
+	data.seek(SeekFrom::Start(FILE_HEADER_OFFSET as u64))?;
+
+	let mut signature:Vec<u8> = vec![0;FILE_HEADER_SIG_LEN];
+	signature.read_value(data)?;
+	return Ok(check_file_header_valid(&signature).is_ok());
+}
+
+pub fn read_file_header(data: &mut (impl Read + Seek)) -> Fallible<Header>
 {
 	data.seek(SeekFrom::Start(FILE_HEADER_OFFSET as u64))?;
 
@@ -37,6 +44,8 @@ pub fn read_file_header(data: &mut File) -> Fallible<Header>
 
 	let mut creator = String::with_capacity(FILE_HEADER_CREATOR_LEN / 2);
 	creator.read_value(data)?;
+	// The file identifier has no version, only the signature and the UTF-16 creator are defined and the rest of the 64 KiB is reserved.
+	let creator_length = creator.encode_utf16().count() * 2;
 
-	return Ok(Header{creator: creator});
+	return Ok(Header{creator, creator_length});
 }
\ No newline at end of file
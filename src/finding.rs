@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::fmt;
+
+use serde::Serialize;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity
+{
+	#[default]
+	Info,
+	Warning,
+	Error,
+}
+
+impl fmt::Display for Severity {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Severity::Info => write!(f, "info"),
+			Severity::Warning => write!(f, "warning"),
+			Severity::Error => write!(f, "error"),
+		}
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
+pub struct Finding
+{
+	pub severity: Severity,
+	pub code: &'static str,
+	pub message: String,
+	pub location: Option<u64>,
+}
+
+impl Finding
+{
+	pub fn new(severity: Severity, code: &'static str, message: String, location: Option<u64>) -> Self
+	{
+		return Finding{severity, code, message, location};
+	}
+}
+
+pub fn highest_severity(findings: &[Finding]) -> Option<Severity>
+{
+	return findings.iter().map(|x| x.severity).max();
+}
+
+pub fn findings_with_severity(findings: &[Finding], severity: Severity) -> impl Iterator<Item = &Finding>
+{
+	return findings.iter().filter(move |x| x.severity == severity);
+}
\ No newline at end of file
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::io::{Read, Seek};
+
+use crate::error::Fallible;
+use crate::{ensure,format_err};
+use crate::block::{self,PayloadBlockState};
+use crate::reader::read_into;
+use crate::VhdxFile;
+
+const SECTORS_PER_BITMAP: u64 = u64::pow(2, 23);
+
+fn read_partial_block<R: Read + Seek>(vhdx: &mut VhdxFile<R>, parents: &mut [VhdxFile<R>], file_offset: u64, virtual_offset: u64, buffer: &mut [u8]) -> Fallible<()>
+{
+	let sector_size = vhdx.metadata.logical_sector_size as u64;
+	let first_sector = virtual_offset / sector_size;
+	let sector_entry = vhdx.sector_blocks.get((first_sector / SECTORS_PER_BITMAP) as usize).ok_or_else(|| {
+		format_err!("No sector bitmap block covers virtual offset 0x{:X}.", virtual_offset)})?;
+	let bitmap = block::read_sector_bitmap(&mut vhdx.file, sector_entry)?;
+
+	// Sectors set in the bitmap are in this file, the rest come from the parent.
+	for (n, sector) in buffer.chunks_mut(sector_size as usize).enumerate()
+	{
+		let offset = n as u64 * sector_size;
+		if bitmap[((first_sector + n as u64) % SECTORS_PER_BITMAP) as usize]
+		{
+			read_into(&mut vhdx.file, file_offset + offset, sector)?;
+		}
+		else
+		{
+			read_virtual(parents, virtual_offset + offset, sector)?;
+		}
+	}
+
+	return Ok(());
+}
+
+fn read_virtual<R: Read + Seek>(chain: &mut [VhdxFile<R>], offset: u64, buffer: &mut [u8]) -> Fallible<()>
+{
+	let (vhdx, parents) = chain.split_first_mut().ok_or_else(|| {
+		format_err!("Virtual offset 0x{:X} is not present in the differencing disk and its parent is not available.", offset)})?;
+	let block_size = vhdx.metadata.file_parameters.block_size as u64;
+
+	let mut position: usize = 0;
+	while position < buffer.len()
+	{
+		let virtual_offset = offset + position as u64;
+		let block_index = (virtual_offset / block_size) as usize;
+		let block_offset = virtual_offset % block_size;
+		let length = u64::min(block_size - block_offset, (buffer.len() - position) as u64) as usize;
+		let target = &mut buffer[position..(position + length)];
+
+		let entry = vhdx.payload_blocks.get(block_index).ok_or_else(|| {
+			format_err!("Virtual offset 0x{:X} is past the last payload block.", virtual_offset)})?;
+		let file_offset = block::block_file_offset(entry, block_offset).ok_or_else(|| {
+			format_err!("Payload block {} at 0x{:X} MiB is past the largest possible file offset.", block_index, entry.file_offset_mb)});
+		match entry.state
+		{
+			PayloadBlockState::FullyPresent => read_into(&mut vhdx.file, file_offset?, target)?,
+			PayloadBlockState::PartiallyPresent => read_partial_block(vhdx, parents, file_offset?, virtual_offset, target)?,
+			PayloadBlockState::NotPresent if vhdx.metadata.file_parameters.has_parent => read_virtual(parents, virtual_offset, target)?,
+			_ => target.fill(0),
+		}
+
+		position += length;
+	}
+
+	return Ok(());
+}
+
+pub fn read_virtual_range<R: Read + Seek>(chain: &mut [VhdxFile<R>], lba: u64, count: u64) -> Fallible<Vec<u8>>
+{
+	ensure!(!chain.is_empty(), "No VHDX file was given to read from.");
+	let sector_size = chain[0].metadata.logical_sector_size as u64;
+	let virtual_disk_size = chain[0].metadata.virtual_disk_size;
+	ensure!(lba.checked_add(count).and_then(|x| x.checked_mul(sector_size)).is_some_and(|x| x <= virtual_disk_size),
+		"Reading {} sectors from sector {} goes past the end of the virtual disk of 0x{:X} bytes.", count, lba, virtual_disk_size);
+
+	let mut buffer: Vec<u8> = vec![0;(count * sector_size) as usize];
+	read_virtual(chain, lba * sector_size, &mut buffer)?;
+
+	return Ok(buffer);
+}
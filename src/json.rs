@@ -0,0 +1,24 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::fmt::UpperHex;
+
+use serde::{Serialize,Serializer,ser::SerializeStruct};
+
+pub fn hex<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error> where T: UpperHex, S: Serializer
+{
+	return serializer.serialize_str(&format!("0x{:X}", value));
+}
+
+pub fn hex_bytes<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error> where T: AsRef<[u8]>, S: Serializer
+{
+	return serializer.serialize_str(&value.as_ref().iter().map(|x| format!("{:02x}", x)).collect::<String>());
+}
+
+pub fn dec_hex<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error> where T: UpperHex + Serialize, S: Serializer
+{
+	let mut state = serializer.serialize_struct("DecHex", 2)?;
+	state.serialize_field("dec", value)?;
+	state.serialize_field("hex", &format!("0x{:X}", value))?;
+	return state.end();
+}
\ No newline at end of file
@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+#[cfg(feature = "mmap")]
+use std::io::Cursor;
+use std::{
+	fmt,
+	fs::File,
+	io::{BufReader, Read, Seek, SeekFrom},
+	path::Path,
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::{Fallible,VhdxError};
+use crate::block::{BatIterator,PayloadBlockState,PayloadEntry,SectorEntry};
+use crate::file_header::Header;
+use crate::log::LogState;
+use crate::metadata::{Metadata,MetadataTable};
+use crate::region::{RegionTable,RegionTableEntry,RegionType};
+use crate::vhd_header::{CorruptHeader,HeaderCopy,VhdHeader};
+
+pub mod annotate;
+pub mod block;
+pub mod chain;
+pub mod checksum;
+pub mod diff;
+pub mod disk;
+pub mod error;
+pub mod extract;
+#[cfg(feature = "capi")]
+pub mod ffi;
+pub mod file_header;
+pub mod finding;
+pub mod io;
+pub mod json;
+pub mod log;
+pub mod maths;
+pub mod metadata;
+pub mod reader;
+pub mod region;
+pub mod vhd_footer;
+pub mod vhd_header;
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+pub enum VhdType
+{
+	Fixed,
+	Dynamic,
+	Differencing,
+}
+
+impl fmt::Display for VhdType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			VhdType::Fixed => write!(f, "Fixed"),
+			VhdType::Dynamic => write!(f, "Dynamic"),
+			VhdType::Differencing => write!(f, "Differencing"),
+		}
+	}
+}
+
+pub fn classify_disk_type(metadata: &Metadata, payload_blocks: Option<&[PayloadEntry]>) -> VhdType
+{
+	// A parent makes the disk differencing and blocks that must stay allocated make it fixed, whatever the BAT holds.
+	if metadata.file_parameters.has_parent
+	{
+		return VhdType::Differencing;
+	}
+	if metadata.file_parameters.leave_block_allocated
+	{
+		return VhdType::Fixed;
+	}
+
+	// Otherwise a disk with every block allocated is treated as fixed, which needs the BAT to tell.
+	match payload_blocks
+	{
+		Some(payload_blocks) if !payload_blocks.iter().any(|x| x.state == PayloadBlockState::NotPresent ||
+			x.state == PayloadBlockState::PartiallyPresent) => return VhdType::Fixed,
+		_ => return VhdType::Dynamic,
+	}
+}
+
+// The checks made while parsing that do not depend on a checksum, rerun against an already parsed file as a standalone audit.
+pub fn verify_structure<R: Seek>(vhdx: &mut VhdxFile<R>) -> Fallible<()>
+{
+	let file_length = vhdx.file.seek(SeekFrom::End(0))?;
+	region::check_region_overlap(&vhdx.region_table)?;
+	for entry in &vhdx.region_table.entries
+	{
+		region::check_region_in_file(entry, file_length)?;
+	}
+
+	let metadata_region = vhdx.region_table.find_required(RegionType::Metadata)?;
+	metadata::check_metadata_items_in_region(&vhdx.metadata_table, metadata_region)?;
+
+	let bat_region = vhdx.region_table.find_required(RegionType::BAT)?;
+	block::check_bat_length(bat_region, &vhdx.metadata)?;
+	// Files opened without reading the BAT have no blocks to check.
+	block::check_payload_offsets(&vhdx.payload_blocks, vhdx.metadata.file_parameters.block_size, file_length)?;
+
+	return Ok(());
+}
+
+const FILE_LENGTH_ALIGNMENT: u64 = u64::pow(1024, 2);
+
+#[derive(Default)]
+pub struct PartialVhdx
+{
+	pub file_length: Option<u64>,
+	pub header: Option<Header>,
+	pub vhdx_header: Option<(usize, VhdHeader)>,
+	pub log_state: Option<LogState>,
+	pub region_table: Option<(usize, RegionTable)>,
+	pub metadata: Option<(MetadataTable, Metadata)>,
+	pub blocks: Option<(Vec<PayloadEntry>, Vec<SectorEntry>)>,
+}
+
+fn keep<T>(result: Fallible<T>, first_error: &mut Option<VhdxError>) -> Option<T>
+{
+	match result
+	{
+		Ok(value) => return Some(value),
+		Err(error) =>
+		{
+			if first_error.is_none()
+			{
+				*first_error = Some(error);
+			}
+			return None;
+		},
+	}
+}
+
+fn parse_stages(data: &mut (impl Read + Seek), partial: &mut PartialVhdx) -> Option<VhdxError>
+{
+	let mut first_error: Option<VhdxError> = None;
+
+	// Each stage is tried whenever the stages it depends on succeeded, so one damaged structure does not hide the others.
+	partial.file_length = keep(data.seek(SeekFrom::End(0)).map_err(VhdxError::from), &mut first_error);
+	if let Some(file_length) = partial.file_length
+	{
+		keep(check_file_length(file_length), &mut first_error);
+	}
+	partial.header = keep(file_header::read_file_header(data), &mut first_error);
+	partial.vhdx_header = keep(vhd_header::read_vhdx_header(data).map(|x| (x.0, x.1)), &mut first_error);
+	if let Some((_, vhdx_header)) = &partial.vhdx_header
+	{
+		partial.log_state = keep(log::read_log(data, vhdx_header.log_id, vhdx_header.log_offset, vhdx_header.log_length), &mut first_error);
+	}
+	partial.region_table = keep(region::read_region(data).map(|x| (x.0, x.1)), &mut first_error);
+
+	let Some((_, region_table)) = &partial.region_table else { return first_error; };
+	let metadata_region = keep(region_table.find_required(RegionType::Metadata), &mut first_error);
+	let bat_region = keep(region_table.find_required(RegionType::BAT), &mut first_error);
+	if let Some(metadata_region) = metadata_region
+	{
+		partial.metadata = keep(metadata::read_metadata(data, metadata_region), &mut first_error);
+	}
+	if let (Some(bat_region), Some((_, metadata))) = (bat_region, &partial.metadata)
+	{
+		partial.blocks = keep(block::read_bat(data, bat_region, metadata, partial.file_length), &mut first_error);
+	}
+
+	return first_error;
+}
+
+// For damaged files, everything that could be parsed is returned along with the first error met on the way.
+pub fn parse_partial<P: AsRef<Path>>(path: P) -> (PartialVhdx, Option<VhdxError>)
+{
+	let mut partial = PartialVhdx::default();
+	let file = match File::open(path)
+	{
+		Ok(file) => file,
+		Err(error) => return (partial, Some(VhdxError::from(error))),
+	};
+
+	let first_error = parse_stages(&mut BufReader::new(file), &mut partial);
+	return (partial, first_error);
+}
+
+fn check_file_length(file_length: u64) -> Fallible<()>
+{
+	// The headers, log and region tables take up the first 1 MiB and everything after is allocated in 1 MiB units.
+	ensure!(file_length >= FILE_LENGTH_ALIGNMENT, "File is too small to be a valid VHDX ({} bytes).", file_length);
+	ensure!(file_length.is_multiple_of(FILE_LENGTH_ALIGNMENT), "File length {} bytes is not a multiple of 1 MiB.", file_length);
+
+	return Ok(());
+}
+
+pub struct VhdxFile<R = File>
+{
+	pub file: R,
+	pub header: Header,
+	pub vhdx_header_offset: usize,
+	pub vhdx_header: VhdHeader,
+	pub corrupt_header: Option<CorruptHeader>,
+	pub other_sequence_number: Option<u64>,
+	pub log_state: LogState,
+	pub region_table_offset: usize,
+	pub region_tables_match: bool,
+	pub region_table: RegionTable,
+	pub metadata_table: MetadataTable,
+	pub metadata: Metadata,
+	pub payload_blocks: Vec<PayloadEntry>,
+	pub sector_blocks: Vec<SectorEntry>,
+}
+
+impl VhdxFile
+{
+	pub fn open<P: AsRef<Path>>(path: P) -> Fallible<VhdxFile>
+	{
+		return VhdxFile::read(path, true, None);
+	}
+
+	pub fn open_metadata_only<P: AsRef<Path>>(path: P) -> Fallible<VhdxFile>
+	{
+		return VhdxFile::read(path, false, None);
+	}
+
+	// Forensic use only, the given header copy is used even when the other copy is newer.
+	pub fn open_with_header<P: AsRef<Path>>(path: P, read_bat: bool, header_copy: HeaderCopy) -> Fallible<VhdxFile>
+	{
+		return VhdxFile::read(path, read_bat, Some(header_copy));
+	}
+
+	pub fn bat_entries(self: &Self) -> Fallible<BatIterator<BufReader<&File>>>
+	{
+		return block::iterate_bat(BufReader::new(&self.file), self.region_table.find_required(RegionType::BAT)?, &self.metadata);
+	}
+
+	#[cfg(feature = "mmap")]
+	fn read<P: AsRef<Path>>(path: P, read_bat: bool, header_copy: Option<HeaderCopy>) -> Fallible<VhdxFile>
+	{
+		let file = File::open(path)?;
+		// The mapping only lives for the parse, changes to the file by another process in that window are not guarded against.
+		let map = unsafe { memmap2::Mmap::map(&file)? };
+		return Ok(VhdxFile::parse(&mut Cursor::new(&map[..]), read_bat, header_copy)?.with_file(file));
+	}
+
+	#[cfg(not(feature = "mmap"))]
+	fn read<P: AsRef<Path>>(path: P, read_bat: bool, header_copy: Option<HeaderCopy>) -> Fallible<VhdxFile>
+	{
+		let file = File::open(path)?;
+		// The BAT and metadata are read a field at a time, buffering turns those into a handful of large reads.
+		let mut data = BufReader::new(file.try_clone()?);
+		return Ok(VhdxFile::parse(&mut data, read_bat, header_copy)?.with_file(file));
+	}
+}
+
+impl<R: Read + Seek> VhdxFile<R>
+{
+	// Readers that cannot seek, such as stdin, need to be read into a Cursor<Vec<u8>> first.
+	pub fn from_reader(mut reader: R, read_bat: bool) -> Fallible<VhdxFile<R>>
+	{
+		return Ok(VhdxFile::parse(&mut reader, read_bat, None)?.with_file(reader));
+	}
+
+	pub fn raw_metadata(self: &mut Self, object_id: Uuid) -> Fallible<Vec<u8>>
+	{
+		let metadata_region = self.region_table.find_required(RegionType::Metadata)?;
+		return metadata::get_raw_metadata(&mut self.file, metadata_region, &self.metadata_table, object_id);
+	}
+}
+
+impl<R> VhdxFile<R>
+{
+	pub fn region(self: &Self, region_type: RegionType) -> Option<&RegionTableEntry>
+	{
+		return self.region_table.find(region_type);
+	}
+
+	fn with_file<F>(self: Self, file: F) -> VhdxFile<F>
+	{
+		let VhdxFile{header, vhdx_header_offset, vhdx_header, corrupt_header, other_sequence_number, log_state, region_table_offset, region_tables_match,
+			region_table, metadata_table, metadata, payload_blocks, sector_blocks, ..} = self;
+		return VhdxFile{file, header, vhdx_header_offset, vhdx_header, corrupt_header, other_sequence_number, log_state, region_table_offset,
+			region_tables_match, region_table, metadata_table, metadata, payload_blocks, sector_blocks};
+	}
+}
+
+impl VhdxFile<()>
+{
+	fn parse(data: &mut (impl Read + Seek), read_bat: bool, header_copy: Option<HeaderCopy>) -> Fallible<VhdxFile<()>>
+	{
+		let file_length = data.seek(SeekFrom::End(0))?;
+		check_file_length(file_length)?;
+		let header = file_header::read_file_header(data)?;
+		let (vhdx_header_offset, vhdx_header, corrupt_header, other_sequence_number) = match header_copy
+		{
+			Some(copy) => vhd_header::read_forced_vhdx_header(data, copy)?,
+			None => vhd_header::read_vhdx_header(data)?,
+		};
+		let log_state = log::read_log(data, vhdx_header.log_id, vhdx_header.log_offset, vhdx_header.log_length)?;
+		let (region_table_offset, region_table, region_tables_match) = region::read_region(data)?;
+
+		let metadata_region = region_table.find_required(RegionType::Metadata)?;
+		let bat_region = region_table.find_required(RegionType::BAT)?;
+
+		let (metadata_table, metadata) = metadata::read_metadata(data, metadata_region)?;
+		let (payload_blocks, sector_blocks) = match read_bat
+		{
+			true => block::read_bat(data, bat_region, &metadata, Some(file_length))?,
+			false => (Vec::new(), Vec::new()),
+		};
+
+		return Ok(VhdxFile{file: (), header, vhdx_header_offset, vhdx_header, corrupt_header, other_sequence_number, log_state, region_table_offset,
+			region_tables_match, region_table, metadata_table, metadata, payload_blocks, sector_blocks});
+	}
+}
\ No newline at end of file
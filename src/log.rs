@@ -0,0 +1,225 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::{
+	cmp::Reverse,
+	fmt,
+	io::{Seek, Read, SeekFrom},
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::{Fallible,VhdxError};
+use crate::checksum::*;
+use crate::json;
+use crate::reader::{read_into,ReadValue};
+use crate::vhd_header::VhdHeader;
+
+const LOG_ENTRY_HEADER_SIG: [u8; LOG_ENTRY_HEADER_SIG_LEN] = [0x6c, 0x6f, 0x67, 0x65];
+const LOG_ENTRY_HEADER_SIG_LEN: usize = 0x4;
+const LOG_ENTRY_HEADER_LEN: usize = 0x40;
+const LOG_ENTRY_HEADER_RESERVED_1_LEN: usize = 0x4;
+const LOG_ENTRY_ALIGNMENT: usize = 0x1000;
+
+#[derive(PartialEq, Debug, Default, Clone, Copy, Serialize)]
+pub enum LogStatus
+{
+	#[default]
+	Empty,
+	Active,
+	PendingReplay,
+}
+
+impl fmt::Display for LogStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			LogStatus::Empty => write!(f, "empty"),
+			LogStatus::Active => write!(f, "active"),
+			LogStatus::PendingReplay => write!(f, "pending replay"),
+		}
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct LogEntryHeader
+{
+	pub checksum: u32,
+	pub entry_length: u32,
+	pub tail: u32,
+	pub sequence_number: u64,
+	pub descriptor_count: u32,
+	pub log_id: Uuid,
+	pub flushed_file_offset: u64,
+	pub last_file_offset: u64,
+}
+
+impl LogEntryHeader
+{
+	pub fn new(data: &mut (impl Read + Seek), offset: u64) -> Fallible<Self>
+	{
+		let mut result = LogEntryHeader::default();
+
+		result.checksum.read_value(data).map_err(|error| VhdxError::read("log entry checksum u32", offset + 0x4, error))?;
+		result.entry_length.read_value(data).map_err(|error| VhdxError::read("log entry length u32", offset + 0x8, error))?;
+		result.tail.read_value(data).map_err(|error| VhdxError::read("log entry tail u32", offset + 0xC, error))?;
+		result.sequence_number.read_value(data).map_err(|error| VhdxError::read("log entry sequence number u64", offset + 0x10, error))?;
+		result.descriptor_count.read_value(data).map_err(|error| VhdxError::read("log entry descriptor count u32", offset + 0x18, error))?;
+		//Skip an internal reserved value...
+		data.seek(SeekFrom::Current(LOG_ENTRY_HEADER_RESERVED_1_LEN as i64))?;
+		result.log_id.read_value(data).map_err(|error| VhdxError::read("log entry log ID Uuid", offset + 0x20, error))?;
+		result.flushed_file_offset.read_value(data).map_err(|error| VhdxError::read("log entry flushed file offset u64", offset + 0x30, error))?;
+		result.last_file_offset.read_value(data).map_err(|error| VhdxError::read("log entry last file offset u64", offset + 0x38, error))?;
+
+		return Ok(result);
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
+pub struct LogState
+{
+	pub status: LogStatus,
+	pub entry_count: u64,
+	pub descriptor_count: u64,
+	pub highest_sequence_number: u64,
+	#[serde(serialize_with = "json::dec_hex")]
+	pub flushed_file_offset: u64,
+	#[serde(serialize_with = "json::dec_hex")]
+	pub last_file_offset: u64,
+}
+
+impl fmt::Display for LogState {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.status {
+			LogStatus::Empty => write!(f, "Log is empty.")?,
+			LogStatus::Active => write!(f, "Log is active with {} entries and no descriptors pending replay.", self.entry_count)?,
+			LogStatus::PendingReplay => write!(f, "Log is active with {} entries pending replay ({} descriptors, highest sequence number 0x{:X}).",
+				self.entry_count, self.descriptor_count, self.highest_sequence_number)?,
+		}
+		if self.status != LogStatus::Empty
+		{
+			write!(f, "\nFile flushed to 0x{:X}, last write at 0x{:X}.", self.flushed_file_offset, self.last_file_offset)?;
+		}
+		return Ok(());
+	}
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+pub enum ConsistencyState
+{
+	Clean,
+	DirtyLogPresent,
+	NoLog,
+}
+
+impl fmt::Display for ConsistencyState {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ConsistencyState::Clean => write!(f, "Disk is consistent, its log has nothing pending replay."),
+			ConsistencyState::DirtyLogPresent => write!(f, "This disk was not cleanly shut down; log replay required before mounting."),
+			ConsistencyState::NoLog => write!(f, "Disk is consistent, it has no active log."),
+		}
+	}
+}
+
+pub fn consistency_state(header: &VhdHeader, log_state: &LogState) -> ConsistencyState
+{
+	if header.log_id.is_nil()
+	{
+		return ConsistencyState::NoLog;
+	}
+	if log_state.status == LogStatus::PendingReplay
+	{
+		return ConsistencyState::DirtyLogPresent;
+	}
+	return ConsistencyState::Clean;
+}
+
+fn check_log_entry_valid(data: &mut (impl Read + Seek), entry_offset: u64, log_end: u64, log_id: &Uuid, entry: &LogEntryHeader) -> Fallible<bool>
+{
+	let entry_length = entry.entry_length as usize;
+	if entry.log_id != *log_id || entry_length < LOG_ENTRY_HEADER_LEN || !entry_length.is_multiple_of(LOG_ENTRY_ALIGNMENT) ||
+		entry_offset + entry_length as u64 > log_end
+	{
+		return Ok(false);
+	}
+
+	let mut entry_buf: Vec<u8> = vec![0;entry_length];
+	read_into(data, entry_offset, &mut entry_buf)?;
+
+	return Ok(check_checksum(entry_buf, LOG_ENTRY_HEADER_SIG_LEN, entry.checksum, "Log entry").is_ok());
+}
+
+fn read_log_entry(data: &mut (impl Read + Seek), log_offset: u64, entry_offset: usize, log_length: usize, log_id: &Uuid) -> Fallible<Option<LogEntryHeader>>
+{
+	data.seek(SeekFrom::Start(log_offset + entry_offset as u64))?;
+
+	let mut signature:Vec<u8> = vec![0;LOG_ENTRY_HEADER_SIG_LEN];
+	signature.read_value(data)?;
+	if signature != LOG_ENTRY_HEADER_SIG
+	{
+		return Ok(None);
+	}
+
+	let entry = LogEntryHeader::new(data, log_offset + entry_offset as u64)?;
+	if !check_log_entry_valid(data, log_offset + entry_offset as u64, log_offset + log_length as u64, log_id, &entry)?
+	{
+		return Ok(None);
+	}
+
+	return Ok(Some(entry));
+}
+
+pub fn read_log(data: &mut (impl Read + Seek), log_id: Uuid, log_offset: u64, log_length: u32) -> Fallible<LogState>
+{
+	let mut state = LogState::default();
+	if log_id.is_nil()
+	{
+		return Ok(state);
+	}
+
+	let mut entries = Vec::<LogEntryHeader>::new();
+	let mut entry_offset: usize = 0;
+	while entry_offset + LOG_ENTRY_HEADER_LEN <= log_length as usize
+	{
+		match read_log_entry(data, log_offset, entry_offset, log_length as usize, &log_id)?
+		{
+			Some(entry) =>
+			{
+				entry_offset += entry.entry_length as usize;
+				entries.push(entry);
+			},
+			None => entry_offset += LOG_ENTRY_ALIGNMENT,
+		}
+	}
+
+	// The active sequence is the run of consecutive sequence numbers ending at the highest one.
+	entries.sort_by_key(|x| Reverse(x.sequence_number));
+	let mut previous_sequence: Option<u64> = None;
+	for entry in &entries
+	{
+		if previous_sequence.is_some_and(|x| entry.sequence_number.checked_add(1) != Some(x))
+		{
+			break;
+		}
+		state.entry_count += 1;
+		state.descriptor_count += entry.descriptor_count as u64;
+		previous_sequence = Some(entry.sequence_number);
+	}
+	state.highest_sequence_number = entries.first().map_or(0, |x| x.sequence_number);
+	// The newest entry records how far the file was known to be written when it was logged.
+	if let Some(newest) = entries.first()
+	{
+		state.flushed_file_offset = newest.flushed_file_offset;
+		state.last_file_offset = newest.last_file_offset;
+	}
+
+	state.status = match (state.entry_count, state.descriptor_count)
+	{
+		(0, _) => LogStatus::Empty,
+		(_, 0) => LogStatus::Active,
+		_ => LogStatus::PendingReplay,
+	};
+
+	return Ok(state);
+}
\ No newline at end of file
@@ -2,96 +2,974 @@
 // Copyright (c) Nick Moss.
 
 use std::{
+	collections::HashSet,
 	env,
-	fmt,
-	fs::File,
 	ffi::OsStr,
-	path::Path,
+	fmt,
+	fs::{File,OpenOptions},
+	io::{BufWriter, Write},
+	path::{Path,PathBuf},
+	process,
 };
 
-use failure::{Error,ensure,Fallible,};
+use uuid::Uuid;
+
+use vhdx_inspector::{annotate,block,chain,checksum,diff,ensure,extract,format_err,file_header,finding,io,json,log,region,vhd_header,VhdType,VhdxFile};
+use vhdx_inspector::disk::{VhdFile,VirtualDisk};
+use vhdx_inspector::chain::DEFAULT_MAX_CHAIN_DEPTH;
+use vhdx_inspector::error::{Fallible,VhdxError,EXIT_OTHER,EXIT_BAD_SIGNATURE,EXIT_CHECKSUM_MISMATCH,EXIT_INVALID_METADATA,EXIT_IO};
+use vhdx_inspector::block::{FragmentationStats,PayloadBlockState,SectorBlockState};
+use vhdx_inspector::finding::{Finding,Severity};
+use vhdx_inspector::log::ConsistencyState;
+use vhdx_inspector::maths::{format_offset,format_size};
+use vhdx_inspector::region::{RegionTable,RegionType};
+use vhdx_inspector::vhd_header::HeaderCopy;
+use vhdx_inspector::metadata::{MetadataTable,MetadataType,ParentLinkageMatch,ParentLocatorType,ParentLocator};
+
+// Bump whenever fields of the --json output are renamed, removed or change shape.
+const JSON_FORMAT_VERSION: u32 = 1;
+
+fn print_help()
+{
+	println!("Vhdx Inspector");
+	println!();
+	println!("Retrieves VHDX file data for debugging.");
+	println!();
+	println!("USAGE:");
+	println!("\tvhdx_inspector [args] <file name>");
+	println!("\t\tDump VHDX information about the given file.");
+	println!("Arguments:");
+	println!("\t-h, --help");
+	println!("\t\tPrint this help message and exit immediately.");
+	println!("\t-f, --follow");
+	println!("\t\tIf the VHDX file is a differencing disk, print the parent");
+	println!("\t\t\tdisk's information and so on up the chain.");
+	println!("\t--strict");
+	println!("\t\tWith --follow, fail when a parent cannot be found instead of stopping the");
+	println!("\t\t\tchain walk with a warning.");
+	println!("\t--max-depth <number>");
+	println!("\t\tWith --follow, stop with an error after this many files (default {}).", DEFAULT_MAX_CHAIN_DEPTH);
+	println!("\t--use-header <1|2>");
+	println!("\t\tRead the file through the first or second VHDX header copy, even if the other");
+	println!("\t\t\tcopy is newer, for example to see the disk before its last header update.");
+	println!("\t--parent <file name>");
+	println!("\t\tWith --follow, read the parent of the first file from this path instead of");
+	println!("\t\t\tthe paths in its parent locator. The linkage GUID is still checked.");
+	println!("\t-q, --quiet");
+	println!("\t\tOnly print validation warnings and errors, prefixed with the file name.");
+	println!("\t-b, --blocks");
+	println!("\t\tPrint the full block status information.");
+	println!("\t--summary");
+	println!("\t\tPrint how many payload and sector blocks are in each state and the");
+	println!("\t\t\tshare of payload blocks that are allocated.");
+	println!("\t--compact");
+	println!("\t\tPrint one line for each region and metadata table entry instead of");
+	println!("\t\t\tthe full listing of its ID, offset, length and flags.");
+	println!("\t--stats");
+	println!("\t\tPrint how fragmented the present payload blocks are in the file: the number");
+	println!("\t\t\tof contiguous runs, the largest gap and whether they are in ascending order.");
+	println!("\t--bitmaps");
+	println!("\t\tPrint how many sectors are set in each present sector bitmap block.");
+	println!("\t--decimal");
+	println!("\t\tPrint file offsets and lengths in decimal as well as hex.");
+	println!("\t-j, --json");
+	println!("\t\tPrint the parsed structures as a single JSON object instead of the report.");
+	println!("\t\t\tBlock arrays are included with --blocks.");
+	println!("\t--yaml");
+	println!("\t\tPrint the same data as --json formatted as YAML.");
+	println!("\t--annotate");
+	println!("\t\tPrint a JSON map of every parsed field's offset, length, raw bytes");
+	println!("\t\t\tand decoded value instead of the report.");
+	println!("\t--json-errors");
+	println!("\t\tPrint errors to stderr as a JSON object, implied by --json and --annotate.");
+	println!("\t--diff-blocks <file name>");
+	println!("\t\tList the payload blocks that differ between the given file and");
+	println!("\t\t\tanother snapshot of the same virtual disk.");
+	println!("\t--compare <file name>");
+	println!("\t\tList the metadata fields and GUIDs that differ from another VHDX file and");
+	println!("\t\t\tcount the payload blocks whose state differs, exiting with 1 if any do.");
+	println!("\t--diff-data");
+	println!("\t\tWith --diff-blocks, also compare the data of blocks present in both.");
+	println!("\t--metadata-only");
+	println!("\t\tPrint only the metadata items, without reading the BAT.");
+	println!("\t--verify");
+	println!("\t\tOnly check that the file parses and its checksums match, printing OK");
+	println!("\t\t\tor the first error and exiting with a non-zero code on failure.");
+	println!("\t--best-effort");
+	println!("\t\tPrint every structure that could be parsed from a damaged file, skipping the");
+	println!("\t\t\tones that failed or depend on one that did, then report the first error.");
+	println!("\t--verify-chain");
+	println!("\t\tOpen every disk up the parent chain, checking each file's checksums and that");
+	println!("\t\t\teach parent matches its child's linkage GUID, printing one line per link.");
+	println!("\t--fix-checksums");
+	println!("\t\tRecompute the VHDX header and region table checksums and write them back");
+	println!("\t\t\tto the file, printing the old and new value of each.");
+	println!("\t--blocks-csv <file name>");
+	println!("\t\tWrite the payload block states to the given CSV file and the sector");
+	println!("\t\t\tbitmap block states to a .sectors.csv file alongside it.");
+	println!("\t--read-lba <sector number>");
+	println!("\t\tPrint a hex dump of the virtual disk starting at the given logical sector,");
+	println!("\t\t\treading through the parent chain for differencing disks.");
+	println!("\t--translate <offset>");
+	println!("\t\tPrint the payload block, its state and the physical file offset holding the");
+	println!("\t\t\tgiven virtual disk byte offset, in decimal or 0x-prefixed hex.");
+	println!("\t--count <number>");
+	println!("\t\tWith --read-lba, the number of sectors to print (default 1).");
+	println!("\t--raw-header");
+	println!("\t\tPrint a hex dump of the file header, both VHDX headers and both region tables");
+	println!("\t\t\twith the decoded value of each field, whether or not they are valid.");
+	println!("\t--dump-region <GUID>");
+	println!("\t\tPrint a hex dump of the region with the given ID from the region table.");
+	println!("\t--dump-to <file name>");
+	println!("\t\tWith --dump-region, write the region's raw bytes to the given file instead.");
+	println!("\t--extract <file name>");
+	println!("\t\tWrite the virtual disk contents to the given file as a raw image.");
+	println!("\t\t\tDifferencing disks are flattened with their parent chain.");
+	println!("\t--output-dir <directory>");
+	println!("\t\tExtract as with --extract to <directory>/<file name>.img, creating the directory");
+	println!("\t\t\tif needed. An existing image is only overwritten with --force.");
+	println!();
+	println!("EXIT CODES:");
+	println!("\t0\tSuccess.");
+	println!("\t{}\tAny other error, or --compare found differences.", EXIT_OTHER);
+	println!("\t{}\tA signature is invalid.", EXIT_BAD_SIGNATURE);
+	println!("\t{}\tA checksum does not match.", EXIT_CHECKSUM_MISMATCH);
+	println!("\t{}\tThe metadata is invalid.", EXIT_INVALID_METADATA);
+	println!("\t{}\tReading the file failed.", EXIT_IO);
+}
+
+fn print_structured(output: &serde_json::Value, yaml_output: bool) -> Fallible<()>
+{
+	// YAML is written from the same value as the JSON so the field names and formatting of both match.
+	match yaml_output
+	{
+		true => print!("{}", serde_yaml::to_string(output)?),
+		false => println!("{}", serde_json::to_string_pretty(output)?),
+	}
+	return Ok(());
+}
+
+fn print_disk_type(file_path: &str, disk: &dyn VirtualDisk)
+{
+	println!("{} file {} is {}.", disk.format_name(), file_path, disk.disk_type());
+}
+
+fn print_vhd_report(file_path: &str, json_output: bool, yaml_output: bool, quiet: bool, decimal: bool) -> Fallible<()>
+{
+	let vhd = VhdFile::open(file_path)?;
+	let (footer_offset, footer) = (vhd.footer_offset, &vhd.footer);
+
+	if json_output
+	{
+		let output = serde_json::json!({"format_version": JSON_FORMAT_VERSION, "tool_version": env!("CARGO_PKG_VERSION"), "files": [{
+			"file_path": file_path,
+			"disk_type": vhd.disk_type().to_string(),
+			"vhd_footer_offset": footer_offset,
+			"vhd_footer": serde_json::to_value(footer)?,
+		}]});
+		return print_structured(&output, yaml_output);
+	}
+	if quiet
+	{
+		return Ok(());
+	}
+
+	println!("Reading VHD file {}.", file_path);
+	print_disk_type(file_path, &vhd);
+	println!();
+	println!("VHD footer at {} says:", format_offset(footer_offset, decimal));
+	println!("	Features are				0x{:X}.", footer.features);
+	println!("	Format version is			0x{:X}.", footer.format_version);
+	println!("	Data offset is				{}.", format_offset(footer.data_offset, decimal));
+	println!("	Creator application is			{}.", footer.creator_application);
+	println!("	Original size is			0x{:X}.", footer.original_size);
+	println!("	Current size is				0x{:X}.", footer.current_size);
+	println!("	Checksum is				0x{:X}.", footer.checksum);
+	println!("	Unique ID is				{}.", footer.unique_id);
+
+	return Ok(());
+}
+
+fn write_blocks_csv(file_path: &str, csv_path: &str) -> Fallible<()>
+{
+	let sector_csv_path = Path::new(csv_path).with_extension("sectors.csv");
+	println!("Writing block states of VHDX file {} to {} and {}.", file_path, csv_path, sector_csv_path.display());
+
+	// The BAT is streamed straight to the files rather than held in memory.
+	let vhdx = VhdxFile::open_metadata_only(file_path)?;
+	let mut payload_out = BufWriter::new(File::create(csv_path)?);
+	let mut sector_out = BufWriter::new(File::create(&sector_csv_path)?);
+	block::write_bat_csv(&mut payload_out, &mut sector_out, vhdx.bat_entries()?)?;
+	payload_out.flush()?;
+	sector_out.flush()?;
+
+	return Ok(());
+}
+
+fn extract_image(file_path: &str, out_path: &str) -> Fallible<()>
+{
+	println!("Extracting VHDX file {} to raw image {}.", file_path, out_path);
+
+	let mut chain = chain::resolve_parent_chain(file_path)?;
+	let mut out = File::create(out_path)?;
+	if chain.len() > 1
+	{
+		println!("Flattening a chain of {} files.", chain.len());
+		extract::flatten_chain(&mut chain, &mut out)?;
+	}
+	else
+	{
+		let vhdx = &mut chain[0];
+		extract::extract_raw(&mut vhdx.file, &vhdx.metadata, &vhdx.payload_blocks, &mut out)?;
+	}
+
+	println!("Wrote {} bytes to {}.", chain[0].metadata.virtual_disk_size, out_path);
+	return Ok(());
+}
+
+fn output_dir_path(file_path: &str, output_dir: &str, force: bool) -> Fallible<String>
+{
+	std::fs::create_dir_all(output_dir)?;
+	let stem = Path::new(file_path).file_stem().ok_or_else(|| format_err!("Cannot name an image after the input path {}.", file_path))?;
+	let out_path = Path::new(output_dir).join(stem).with_extension("img");
+	ensure!(force || !out_path.exists(), "{} already exists, use --force to overwrite it.", out_path.display());
+
+	return Ok(out_path.to_string_lossy().into_owned());
+}
+
+fn fix_checksums(file_path: &str) -> Fallible<()>
+{
+	let mut file = OpenOptions::new().read(true).write(true).open(file_path)?;
+	ensure!(file_header::is_vhdx_file(&mut file)?, BadSignature, "File signature is invalid, refusing to fix checksums.");
+
+	// Every signature is checked before anything is written.
+	let mut fixes = vhd_header::recompute_vhdx_header_checksums(&mut file)?;
+	fixes.extend(region::recompute_region_checksums(&mut file)?);
+
+	for fix in &fixes
+	{
+		if fix.old == fix.new
+		{
+			println!("{} at 0x{:X} checksum 0x{:08X} is already correct.", fix.type_name, fix.offset, fix.old);
+		}
+		else
+		{
+			checksum::write_checksum(&mut file, fix)?;
+			println!("{} at 0x{:X} checksum changed from 0x{:08X} to 0x{:08X}.", fix.type_name, fix.offset, fix.old, fix.new);
+		}
+	}
+	file.sync_all()?;
+
+	return Ok(());
+}
+
+fn print_hexdump(base_offset: u64, data: &[u8])
+{
+	for (n, line) in data.chunks(16).enumerate()
+	{
+		let hex: Vec<String> = line.iter().map(|x| format!("{:02X}", x)).collect();
+		let ascii: String = line.iter().map(|x| if x.is_ascii_graphic() || *x == b' ' {*x as char} else {'.'}).collect();
+		println!("{:016X}  {:<47}  {}", base_offset + (n * 16) as u64, hex.join(" "), ascii);
+	}
+}
+
+fn print_raw_headers(file_path: &str) -> Fallible<()>
+{
+	let mut file = File::open(file_path)?;
+	for structure in annotate::annotate_fixed_headers(&mut file)?
+	{
+		println!("{} at 0x{:X}, signature '{}' {}:", structure.name, structure.offset, String::from_utf8_lossy(structure.signature),
+			if structure.signature_valid() {"matches"} else {"does not match"});
+		print_hexdump(structure.offset as u64, &structure.raw);
+		println!();
+		for field in &structure.fields
+		{
+			println!("	0x{:06X} +0x{:03X}	{:<40}	{}", field.offset, field.length, field.name, field.value);
+		}
+		println!();
+	}
+
+	return Ok(());
+}
+
+fn dump_region(file_path: &str, region_id: &Uuid, out_path: Option<&str>, decimal: bool) -> Fallible<()>
+{
+	let mut vhdx = VhdxFile::open_metadata_only(file_path)?;
+	let entry = vhdx.region_table.find_by_id(region_id);
+	ensure!(entry.is_some(), "Region table does not contain a region with ID {}.", region_id);
+	let entry = entry.unwrap();
+	let data = region::read_region_bytes(&mut vhdx.file, entry)?;
+
+	match out_path
+	{
+		Some(out_path) =>
+		{
+			File::create(out_path)?.write_all(&data)?;
+			println!("Wrote 0x{:X} bytes of {} region {} to {}.", data.len(), entry.region_type, region_id, out_path);
+		},
+		None =>
+		{
+			println!("Region {} at offset {} of VHDX file {}:", region_id, format_offset(entry.object_offset, decimal), file_path);
+			print_hexdump(entry.object_offset, &data);
+		},
+	}
+
+	return Ok(());
+}
+
+fn read_lba_range(file_path: &str, lba: u64, count: u64, max_depth: u64) -> Fallible<()>
+{
+	let mut chain = chain::resolve_parent_chain_with_depth(file_path, max_depth)?;
+	let sector_size = chain[0].metadata.logical_sector_size as u64;
+	let data = io::read_virtual_range(&mut chain, lba, count)?;
+
+	println!("{} sectors from sector {} of VHDX file {}:", count, lba, file_path);
+	print_hexdump(lba * sector_size, &data);
+	return Ok(());
+}
+
+fn translate_offset(file_path: &str, virtual_offset: u64, decimal: bool) -> Fallible<()>
+{
+	let vhdx = VhdxFile::open(file_path)?;
+	let virtual_disk_size = vhdx.metadata.virtual_disk_size;
+	ensure!(virtual_offset < virtual_disk_size, "Virtual offset 0x{:X} is past the end of the virtual disk of 0x{:X} bytes.",
+		virtual_offset, virtual_disk_size);
+	let block_size = vhdx.metadata.file_parameters.block_size as u64;
+	let block_index = virtual_offset / block_size;
+	let entry = vhdx.payload_blocks.get(block_index as usize).ok_or_else(|| format_err!("No payload block covers virtual offset 0x{:X}.", virtual_offset))?;
+
+	println!("Virtual offset {} is {} into payload block {}, which is {}.", format_offset(virtual_offset, decimal),
+		format_offset(virtual_offset % block_size, decimal), block_index, entry.state);
+	match block::virtual_to_physical(&vhdx.metadata, &vhdx.payload_blocks, virtual_offset)
+	{
+		Some(physical_offset) => println!("Physical file offset is {}.", format_offset(physical_offset, decimal)),
+		None => println!("The block is not allocated in this file, so the offset has no physical location."),
+	}
+	if entry.state == PayloadBlockState::PartiallyPresent
+	{
+		println!("Only sectors set in the block's sector bitmap are stored there, the rest are read from the parent.");
+	}
+
+	return Ok(());
+}
+
+fn parse_number(value: &str) -> Option<u64>
+{
+	match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X"))
+	{
+		Some(hex) => return u64::from_str_radix(hex, 16).ok(),
+		None => return value.parse().ok(),
+	}
+}
+
+fn verify_chain(file_path: &str, max_depth: u64) -> Fallible<()>
+{
+	let chain = chain::resolve_parent_chain_with(file_path, max_depth, |link| {
+		let linkage = if link.linkage == ParentLinkageMatch::Linkage2 {"parent_linkage2"} else {"parent_linkage"};
+		println!("Link {} from {} to {}, located from {}, is consistent: the parent's Data Write GUID matches {}.",
+			link.link, link.child_path.display(), link.parent_path.display(), link.source, linkage);
+	})?;
+
+	println!("Chain of {} disk{} is consistent.", chain.len(), if chain.len() == 1 {""} else {"s"});
+	return Ok(());
+}
+
+fn collect_findings(vhdx: &VhdxFile) -> Vec<Finding>
+{
+	let VhdxFile{region_table, metadata, ..} = vhdx;
+	let mut findings = Vec::<Finding>::new();
+	let metadata_offset = region_table.find(RegionType::Metadata).map(|x| x.object_offset);
+
+	if let Some(corrupt) = &vhdx.corrupt_header
+	{
+		findings.push(Finding::new(Severity::Warning, "vhdx-header-corrupt",
+			format!("VHDX header copy is corrupt, using the copy at 0x{:X}: {}", vhdx.vhdx_header_offset, corrupt.error),
+			Some(corrupt.offset as u64)));
+	}
+	if vhdx.vhdx_header.sequence_number == 0
+	{
+		findings.push(Finding::new(Severity::Warning, "vhdx-header-sequence-zero",
+			String::from("VHDX header sequence number is 0, the header may have been zeroed or rewritten."),
+			Some(vhdx.vhdx_header_offset as u64)));
+	}
+	// Each update writes the older copy with the next sequence number, so the two copies normally differ by exactly one.
+	if let Some(other) = vhdx.other_sequence_number.filter(|x| vhdx.vhdx_header.sequence_number.abs_diff(*x) > 1)
+	{
+		findings.push(Finding::new(Severity::Warning, "vhdx-header-sequence-gap",
+			format!("VHDX header sequence numbers 0x{:X} and 0x{:X} differ by more than 1, the headers may have been tampered with.",
+				vhdx.vhdx_header.sequence_number, other),
+			Some(vhdx.vhdx_header_offset as u64)));
+	}
+	if !vhdx.vhdx_header.is_supported_version()
+	{
+		findings.push(Finding::new(Severity::Warning, "vhdx-version-unsupported",
+			format!("Unsupported VHDX version {}, fields may be misinterpreted.", vhdx.vhdx_header.version),
+			Some(vhdx.vhdx_header_offset as u64)));
+	}
+	if !vhdx.vhdx_header.is_supported_log_version()
+	{
+		findings.push(Finding::new(Severity::Warning, "log-version-unsupported",
+			format!("Unsupported VHDX log version {}, the log may be misinterpreted.", vhdx.vhdx_header.log_version),
+			Some(vhdx.vhdx_header_offset as u64)));
+	}
+	if log::consistency_state(&vhdx.vhdx_header, &vhdx.log_state) == ConsistencyState::DirtyLogPresent
+	{
+		findings.push(Finding::new(Severity::Warning, "log-replay-required",
+			format!("Log has {} entries pending replay, the disk contents are not consistent until it is replayed.", vhdx.log_state.entry_count),
+			Some(vhdx.vhdx_header.log_offset)));
+	}
+	if let Some(file_length) = vhdx.file.metadata().ok().map(|x| x.len()).filter(|x| vhdx.log_state.last_file_offset > *x)
+	{
+		findings.push(Finding::new(Severity::Warning, "log-last-offset-past-end",
+			format!("Log records a last write at 0x{:X} but the file is only 0x{:X} bytes long, it may have been truncated.",
+				vhdx.log_state.last_file_offset, file_length),
+			Some(vhdx.vhdx_header.log_offset)));
+	}
+	if !vhdx.region_tables_match
+	{
+		findings.push(Finding::new(Severity::Warning, "region-table-mismatch",
+			format!("Region table copies differ or one is invalid, using the copy at 0x{:X}.", vhdx.region_table_offset),
+			Some(vhdx.region_table_offset as u64)));
+	}
+	for entry in region_table.entries.iter().filter(|x| x.region_type == RegionType::Unknown)
+	{
+		findings.push(Finding::new(Severity::Info, "region-unknown",
+			format!("Optional region {} is not recognised and was ignored.", entry.object_id), Some(entry.object_offset)));
+	}
+	for entry in vhdx.metadata_table.entries.iter().filter(|x| x.metadata_type == MetadataType::Unknown)
+	{
+		findings.push(Finding::new(Severity::Info, "metadata-unknown",
+			format!("Optional metadata item {} is not recognised and was ignored, it is 0x{:X} bytes at offset 0x{:X} in the metadata region.",
+				entry.object_id, entry.object_length, entry.object_offset),
+			metadata_offset.map(|x| x + entry.object_offset as u64)));
+	}
+	if metadata.physical_sector_size_assumed
+	{
+		findings.push(Finding::new(Severity::Info, "physical-sector-size-zero",
+			format!("Physical sector size is recorded as 0, defaulted to 0x{:X}.", metadata.physical_sector_size),
+			metadata_offset));
+	}
+	// Both sizes are valid on their own by now, a logical sector larger than the physical one is only unusual.
+	// A defaulted physical size says nothing about the disk, so it is not compared.
+	if !metadata.physical_sector_size_assumed && metadata.logical_sector_size > metadata.physical_sector_size
+	{
+		findings.push(Finding::new(Severity::Warning, "logical-sector-larger-than-physical",
+			format!("Logical sector size 0x{:X} is larger than the physical sector size 0x{:X}, which the specification does not expect.",
+				metadata.logical_sector_size, metadata.physical_sector_size),
+			metadata_offset));
+	}
+	let not_present = vhdx.payload_blocks.iter().filter(|x| x.state == PayloadBlockState::NotPresent).count();
+	if metadata.file_parameters.leave_block_allocated && not_present > 0
+	{
+		findings.push(Finding::new(Severity::Warning, "leave-block-allocated-not-present",
+			format!("File parameter 'LeaveBlockAllocated' is set but {} payload blocks are not present.", not_present),
+			metadata_offset));
+	}
+	if let Some(locator) = &metadata.parent_locator_dict
+	{
+		if locator.locator_type == ParentLocatorType::Unknown
+		{
+			findings.push(Finding::new(Severity::Warning, "parent-locator-unknown",
+				format!("Parent locator type {} is not recognised, the parent cannot be located.", locator.locator_type_id),
+				metadata_offset));
+		}
+		for key in locator.unknown_keys()
+		{
+			findings.push(Finding::new(Severity::Warning, "parent-locator-unknown-key",
+				format!("Parent locator key '{}' is not recognised and was ignored.", key), metadata_offset));
+		}
+	}
+
+	return findings;
+}
+
+fn print_findings(findings: &[Finding], decimal: bool)
+{
+	match finding::highest_severity(findings)
+	{
+		Some(highest) =>
+		{
+			println!("Validation findings (highest severity is {}):", highest);
+			for severity in [Severity::Error, Severity::Warning, Severity::Info]
+			{
+				for entry in finding::findings_with_severity(findings, severity)
+				{
+					match entry.location
+					{
+						Some(location) => println!("	[{}] {} at {}: {}", entry.severity, entry.code, format_offset(location, decimal), entry.message),
+						None => println!("	[{}] {}: {}", entry.severity, entry.code, entry.message),
+					}
+				}
+			}
+		},
+		None => println!("Validation findings: none."),
+	}
+	println!();
+}
+
+fn print_quiet_findings(file_path: &str, findings: &[Finding], decimal: bool)
+{
+	for severity in [Severity::Error, Severity::Warning]
+	{
+		for entry in finding::findings_with_severity(findings, severity)
+		{
+			match entry.location
+			{
+				Some(location) => println!("{}: [{}] {} at {}: {}", file_path, entry.severity, entry.code, format_offset(location, decimal),
+					entry.message),
+				None => println!("{}: [{}] {}: {}", file_path, entry.severity, entry.code, entry.message),
+			}
+		}
+	}
+}
+
+fn print_compare(file_path: &str, other_path: &str) -> Fallible<bool>
+{
+	println!("Comparing VHDX file {} against {}.", file_path, other_path);
+
+	let vhdx = VhdxFile::open(file_path)?;
+	let other = VhdxFile::open(other_path)?;
+	let differences = diff::diff_fields(&vhdx, &other);
+	let changed_blocks = diff::count_state_differences(&vhdx.payload_blocks, &other.payload_blocks);
+
+	let width = differences.iter().map(|x| x.field.len()).max().unwrap_or(0) + 1;
+	for difference in &differences
+	{
+		println!("	{:<width$} {} vs {}", format!("{}:", difference.field), difference.first, difference.second, width = width);
+	}
+	println!("{} fields differ, {} payload blocks differ in state.", differences.len(), changed_blocks);
+
+	return Ok(differences.is_empty() && changed_blocks == 0);
+}
+
+fn print_block_diff(file_path: &str, other_path: &str, compare_data: bool) -> Fallible<()>
+{
+	println!("Comparing payload blocks of VHDX file {} against {}.", file_path, other_path);
+
+	let VhdxFile{file: mut vhdx_file, vhdx_header, metadata, payload_blocks, ..} = VhdxFile::open(file_path)?;
+	let VhdxFile{file: mut other_file, vhdx_header: other_header, metadata: other_metadata, payload_blocks: other_blocks, ..} =
+		VhdxFile::open(other_path)?;
+
+	if metadata.virtual_disk_id != other_metadata.virtual_disk_id
+	{
+		println!("Warning: Virtual disk IDs differ ({} and {}), the files may not be snapshots of the same disk.",
+			metadata.virtual_disk_id, other_metadata.virtual_disk_id);
+	}
+	if vhdx_header.data_write_id == other_header.data_write_id
+	{
+		println!("Data Write GUID {} is identical in both files, no data changes are expected.", vhdx_header.data_write_id);
+	}
+	if payload_blocks.len() != other_blocks.len()
+	{
+		println!("Warning: Payload block counts differ ({} and {}), only the first {} are compared.",
+			payload_blocks.len(), other_blocks.len(), payload_blocks.len().min(other_blocks.len()));
+	}
+	ensure!(!compare_data || metadata.file_parameters.block_size == other_metadata.file_parameters.block_size,
+		"Block sizes differ (0x{:X} and 0x{:X}), block data cannot be compared.",
+		metadata.file_parameters.block_size, other_metadata.file_parameters.block_size);
+
+	let changed = diff::diff_payload_blocks(&mut vhdx_file, &payload_blocks, &mut other_file, &other_blocks,
+		metadata.file_parameters.block_size, compare_data)?;
+
+	println!("{} payload blocks changed.", changed.len());
+	for block in changed
+	{
+		let first = &payload_blocks[block.index as usize];
+		let second = &other_blocks[block.index as usize];
+		if block.state_or_offset_changed
+		{
+			println!("	Block {} was {} at offset {}MiB and is {} at offset {}MiB.", block.index,
+				first.state, first.file_offset_mb, second.state, second.file_offset_mb);
+		}
+		if block.data_changed
+		{
+			println!("	Block {} data differs.", block.index);
+		}
+	}
+
+	return Ok(());
+}
+
+struct Report<'a>
+{
+	file_path: &'a str,
+	vhdx: &'a VhdxFile,
+	file_length: u64,
+	allocated_bytes: u64,
+	sector_bitmaps: Option<Vec<(usize, usize, usize)>>,
+	fragmentation: Option<FragmentationStats>,
+	findings: &'a [Finding],
+	decimal: bool,
+	compact: bool,
+}
+
+fn print_state_counts<T: fmt::Display>(counts: &[(&T, usize)], total: usize)
+{
+	for (state, count) in counts
+	{
+		println!("		{:<20}{:>12} ({:.1}%).", format!("{}:", state), count, allocated_percent(*count as u64, total as u64));
+	}
+}
+
+fn print_block_summary(vhdx: &VhdxFile)
+{
+	let payload_total = vhdx.payload_blocks.len();
+	let sector_total = vhdx.sector_blocks.len();
+	let allocated = vhdx.payload_blocks.iter()
+		.filter(|x| x.state == PayloadBlockState::FullyPresent || x.state == PayloadBlockState::PartiallyPresent).count();
+
+	println!("Block summary:");
+	println!("	{} payload blocks:", payload_total);
+	print_state_counts(&block::count_states(&block::PAYLOAD_BLOCK_STATES, vhdx.payload_blocks.iter().map(|x| &x.state)), payload_total);
+	println!("	{} sector blocks:", sector_total);
+	print_state_counts(&block::count_states(&block::SECTOR_BLOCK_STATES, vhdx.sector_blocks.iter().map(|x| &x.state)), sector_total);
+	println!("	Allocated payload blocks:	{} ({:.1}%), sparse {} ({:.1}%).", allocated,
+		allocated_percent(allocated as u64, payload_total as u64), payload_total - allocated,
+		allocated_percent((payload_total - allocated) as u64, payload_total as u64));
+	println!();
+}
+
+fn print_fragmentation(stats: &FragmentationStats)
+{
+	println!("Fragmentation:");
+	println!("	Present payload blocks:	{}.", stats.present_blocks);
+	println!("	Contiguous runs:	{} (average length {:.1} blocks).", stats.runs, stats.average_run_length);
+	println!("	Largest gap:	{}MiB.", stats.largest_gap_mb);
+	match stats.ascending
+	{
+		true => println!("	File order:	ascending, blocks are stored in virtual disk order."),
+		false => println!("	File order:	not ascending, blocks have been allocated out of virtual disk order."),
+	}
+	println!();
+}
+
+// The alternate form of the structure reports adds the decimal value after each hex offset and length.
+fn display(value: &impl fmt::Display, decimal: bool) -> String
+{
+	if decimal
+	{
+		return format!("{:#}", value);
+	}
+	return value.to_string();
+}
+
+fn print_compact_regions(region_table: &RegionTable, decimal: bool)
+{
+	for entry in &region_table.entries
+	{
+		let name = match entry.region_type
+		{
+			RegionType::BAT => String::from("BAT"),
+			RegionType::Metadata => String::from("Metadata"),
+			RegionType::Unknown => format!("Unknown {}", entry.object_id),
+		};
+		println!("	{} @ {} len {}{}.", name, format_offset(entry.object_offset, decimal), format_offset(entry.object_length as u64, decimal),
+			if entry.required {" (required)"} else {""});
+	}
+	println!();
+}
+
+fn print_compact_metadata_table(metadata_table: &MetadataTable, decimal: bool)
+{
+	for entry in &metadata_table.entries
+	{
+		let name = match entry.metadata_type
+		{
+			MetadataType::Unknown => format!("Unknown {}", entry.object_id),
+			_ => entry.metadata_type.to_string(),
+		};
+		let flags: Vec<&str> = [(entry.is_required, "required"), (entry.is_user, "user"), (entry.is_virtual_disk, "virtual disk")].iter()
+			.filter(|x| x.0).map(|x| x.1).collect();
+		println!("	{} @ {} len {}{}.", name, format_offset(entry.object_offset as u64, decimal), format_offset(entry.object_length as u64, decimal),
+			if flags.is_empty() {String::new()} else {format!(" ({})", flags.join(", "))});
+	}
+	println!();
+}
+
+fn print_report(report: &Report, print_blocks: bool, print_summary: bool)
+{
+	let vhdx = report.vhdx;
+	let vhdx_header = &vhdx.vhdx_header;
+	let metadata = &vhdx.metadata;
+
+	print_disk_type(report.file_path, vhdx);
+	println!("{}", log::consistency_state(vhdx_header, &vhdx.log_state));
+	println!("File signature is created by {}.", vhdx.header.creator);
+	println!("File identifier signature '{}' matched, the creator uses {} of {} bytes.", String::from_utf8_lossy(&file_header::FILE_HEADER_SIG),
+		vhdx.header.creator_length, file_header::FILE_HEADER_CREATOR_LEN);
+	println!();
+	println!("VHDX header at {} says:", format_offset(vhdx.vhdx_header_offset as u64, report.decimal));
+	print!("{}", display(vhdx_header, report.decimal));
+	println!();
+
+	println!("{}", vhdx.log_state);
+	println!();
+
+	println!("Region table contains:");
+	if report.compact
+	{
+		print_compact_regions(&vhdx.region_table, report.decimal);
+	}
+	else
+	{
+		print!("{}", display(&vhdx.region_table, report.decimal));
+	}
+
+	if print_blocks
+	{
+		println!("Payload blocks:");
+		for (block_index, payload) in vhdx.payload_blocks.iter().enumerate()
+		{
+			println!("	Block {} at offset {}MiB is {} (raw 0x{:016X}).", block_index, payload.file_offset_mb, payload.state, payload.raw);
+		}
+		println!();
+
+		println!("Sector blocks:");
+		for (block_index, sector) in vhdx.sector_blocks.iter().enumerate()
+		{
+			println!("	Block {} at offset {}MiB is {} (raw 0x{:016X}).", block_index, sector.file_offset_mb, sector.state, sector.raw);
+		}
+		println!();
+	}
+
+	if let Some(sector_bitmaps) = &report.sector_bitmaps
+	{
+		println!("Sector bitmaps:");
+		for (block_index, set_sectors, total_sectors) in sector_bitmaps
+		{
+			println!("	Block {} has {} of {} sectors set.", block_index, set_sectors, total_sectors);
+		}
+		println!();
+	}
+
+	println!("Metadata table contains:");
+	if report.compact
+	{
+		print_compact_metadata_table(&vhdx.metadata_table, report.decimal);
+	}
+	else
+	{
+		print!("{}", display(&vhdx.metadata_table, report.decimal));
+	}
+	if let Some(metadata_region) = vhdx.region(RegionType::Metadata)
+	{
+		let region_length = metadata_region.object_length as u64;
+		println!("Metadata region free space: {} of {}.", format_offset(vhdx.metadata_table.free_space(region_length), report.decimal),
+			format_offset(region_length, report.decimal));
+		println!();
+	}
+
+	println!("Metadata contains:");
+	print!("{}", display(metadata, report.decimal));
+
+	println!("Allocation:");
+	println!("	Virtual disk size on disk:		{}.", format_size(report.file_length));
+	println!("	Allocated payload size:			{}.", format_size(report.allocated_bytes));
+	println!("	Allocated of virtual disk size:		{:.1}%.", allocated_percent(report.allocated_bytes, metadata.virtual_disk_size));
+	if let VhdType::Dynamic = vhdx.disk_type()
+	{
+		let reclaimable = block::estimate_reclaimable_bytes(&vhdx.payload_blocks, &vhdx.sector_blocks,
+			metadata.file_parameters.block_size, report.file_length);
+		println!("	Estimated reclaimable by compaction:	{:.1} GiB (0x{:X}).", bytes_to_gib(reclaimable), reclaimable);
+	}
+	println!();
+
+	if print_summary
+	{
+		print_block_summary(vhdx);
+	}
+	if let Some(fragmentation) = &report.fragmentation
+	{
+		print_fragmentation(fragmentation);
+	}
+
+	print_findings(report.findings, report.decimal);
+}
 
-use crate::block::PayloadBlockState;
-use crate::region::RegionType;
-use crate::metadata::{MetadataType,ParentLocatorType,ParentLocator};
+fn print_best_effort(file_path: &str, decimal: bool) -> Fallible<()>
+{
+	let (partial, first_error) = vhdx_inspector::parse_partial(file_path);
+	println!("Reading VHDX file {} on a best effort basis.", file_path);
+	println!();
 
-mod block;
-mod checksum;
-mod file_header;
-mod maths;
-mod metadata;
-mod reader;
-mod region;
-mod vhd_header;
+	match &partial.header
+	{
+		Some(header) => println!("File signature is created by {}.", header.creator),
+		None => println!("File header could not be read."),
+	}
+	match &partial.vhdx_header
+	{
+		Some((offset, vhdx_header)) =>
+		{
+			println!("VHDX header at {} says:", format_offset(*offset as u64, decimal));
+			print!("{}", display(vhdx_header, decimal));
+		},
+		None => println!("VHDX header could not be read."),
+	}
+	println!();
+	match &partial.log_state
+	{
+		Some(log_state) => println!("{}", log_state),
+		None => println!("Log could not be read."),
+	}
+	println!();
+	match &partial.region_table
+	{
+		Some((offset, region_table)) =>
+		{
+			println!("Region table at {} contains:", format_offset(*offset as u64, decimal));
+			print!("{}", display(region_table, decimal));
+		},
+		None => println!("Region table could not be read."),
+	}
+	match &partial.metadata
+	{
+		Some((metadata_table, metadata)) =>
+		{
+			println!("Metadata table contains:");
+			print!("{}", display(metadata_table, decimal));
+			println!("Metadata contains:");
+			print!("{}", display(metadata, decimal));
+		},
+		None => println!("Metadata could not be read."),
+	}
+	match &partial.blocks
+	{
+		Some((payload_blocks, sector_blocks)) =>
+		{
+			println!("Block summary:");
+			println!("	{} payload blocks:", payload_blocks.len());
+			print_state_counts(&block::count_states(&block::PAYLOAD_BLOCK_STATES, payload_blocks.iter().map(|x| &x.state)), payload_blocks.len());
+			println!("	{} sector blocks:", sector_blocks.len());
+			print_state_counts(&block::count_states(&block::SECTOR_BLOCK_STATES, sector_blocks.iter().map(|x| &x.state)), sector_blocks.len());
+		},
+		None => println!("BAT could not be read."),
+	}
 
-enum VhdType
-{
-	Fixed,
-	Dynamic,
-	Differencing,
+	// The exit code still reflects the damage, the first error is reported by main like any other.
+	match first_error
+	{
+		Some(error) => return Err(error),
+		None => return Ok(()),
+	}
 }
 
-impl fmt::Display for VhdType {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		match *self {
-			VhdType::Fixed => write!(f, "Fixed"),
-			VhdType::Dynamic => write!(f, "Dynamic"),
-			VhdType::Differencing => write!(f, "Differencing"),
-		}
-	}
+fn print_metadata_only(file_path: &str, vhdx: &VhdxFile, decimal: bool)
+{
+	print_disk_type(file_path, vhdx);
+	println!("VHDX file {} metadata contains:", file_path);
+	print!("{}", display(&vhdx.metadata, decimal));
 }
 
-fn print_help()
+fn json_state_counts<T: fmt::Display>(counts: &[(&T, usize)]) -> serde_json::Value
 {
-	println!("Vhdx Inspector");
-	println!();
-	println!("Retrieves VHDX file data for debugging.");
-	println!();
-	println!("USAGE:");
-	println!("\tvhdx_inspector [args] <file name>");
-	println!("\t\tDump VHDX information about the given file.");
-	println!("Arguments:");
-	println!("\t-h, --help");
-	println!("\t\tPrint this help message and exit immediately.");
-	println!("\t-f, --follow");
-	println!("\t\tIf the VHDX file is a differencing disk, print the parent");
-	println!("\t\t\tdisk's information and so on up the chain.");
-	println!("\t-b, --blocks");
-	println!("\t\tPrint the full block status information.");
+	return counts.iter().map(|(state, count)| (state.to_string(), serde_json::json!(count))).collect::<serde_json::Map<_, _>>().into();
 }
 
-fn calc_parent_path(locator: &ParentLocator, child_path: &Path) -> Fallible<String>
+fn json_report(report: &Report, include_blocks: bool, include_summary: bool) -> Fallible<serde_json::Value>
 {
-	if !locator.relative_path.is_empty() && 
-		child_path.parent().unwrap().join(&locator.relative_path).exists()
+	let vhdx = report.vhdx;
+	let mut value = serde_json::json!({
+		"file_path": report.file_path,
+		"disk_type": vhdx.disk_type().to_string(),
+		"creator": vhdx.header.creator,
+		"creator_length": vhdx.header.creator_length,
+		"vhdx_header_offset": json::dec_hex(&vhdx.vhdx_header_offset, serde_json::value::Serializer)?,
+		"vhdx_header": serde_json::to_value(&vhdx.vhdx_header)?,
+		"log": serde_json::to_value(&vhdx.log_state)?,
+		"consistency": serde_json::to_value(log::consistency_state(&vhdx.vhdx_header, &vhdx.log_state))?,
+		"region_table": serde_json::to_value(&vhdx.region_table)?,
+		"metadata_table": serde_json::to_value(&vhdx.metadata_table)?,
+		"metadata_free_space": vhdx.region(RegionType::Metadata).map(|x| vhdx.metadata_table.free_space(x.object_length as u64)),
+		"metadata": serde_json::to_value(&vhdx.metadata)?,
+		"file_length": report.file_length,
+		"allocated_bytes": report.allocated_bytes,
+		"allocated_percent": allocated_percent(report.allocated_bytes, vhdx.metadata.virtual_disk_size),
+		"findings": serde_json::to_value(report.findings)?,
+	});
+	if let Some(sector_bitmaps) = &report.sector_bitmaps
 	{
-		println!("Located parent from relative path '{}'.", &locator.relative_path);
-		return Ok(child_path.parent().unwrap().join(locator.relative_path.clone()).canonicalize()?.to_str().unwrap().to_string());
+		value["sector_bitmaps"] = sector_bitmaps.iter()
+			.map(|(block_index, set_sectors, total_sectors)| serde_json::json!({"block_index": block_index, "set_sectors": set_sectors,
+				"total_sectors": total_sectors})).collect();
 	}
-	else if !locator.volume_path.is_empty() &&
-		Path::new(OsStr::new(locator.volume_path.as_str())).exists()
+	if let Some(fragmentation) = &report.fragmentation
 	{
-		println!("Located parent from volume path '{}'.", &locator.volume_path);
-		return Ok(locator.volume_path.clone());
+		value["fragmentation"] = serde_json::to_value(fragmentation)?;
 	}
-	else if !locator.absolute_win32_path.is_empty() &&
-		Path::new(OsStr::new(locator.absolute_win32_path.as_str())).exists()
+	if include_summary
 	{
-		println!("Located parent from absolute path '{}'.", &locator.absolute_win32_path);
-		return Ok(locator.absolute_win32_path.clone());
+		value["block_summary"] = serde_json::json!({
+			"payload_blocks": json_state_counts(&block::count_states(&block::PAYLOAD_BLOCK_STATES, vhdx.payload_blocks.iter().map(|x| &x.state))),
+			"sector_blocks": json_state_counts(&block::count_states(&block::SECTOR_BLOCK_STATES, vhdx.sector_blocks.iter().map(|x| &x.state))),
+		});
 	}
-	else
+	if include_blocks
+	{
+		value["payload_blocks"] = serde_json::to_value(&vhdx.payload_blocks)?;
+		value["sector_blocks"] = serde_json::to_value(&vhdx.sector_blocks)?;
+	}
+
+	return Ok(value);
+}
+
+fn count_sector_bitmaps(vhdx: &mut VhdxFile) -> Fallible<Vec<(usize, usize, usize)>>
+{
+	let mut counts = Vec::<(usize, usize, usize)>::new();
+	for (block_index, sector) in vhdx.sector_blocks.iter().enumerate().filter(|(_, x)| x.state != SectorBlockState::NotPresent)
+	{
+		let bitmap = block::read_sector_bitmap(&mut vhdx.file, sector)?;
+		counts.push((block_index, bitmap.iter().filter(|x| **x).count(), bitmap.len()));
+	}
+
+	return Ok(counts);
+}
+
+fn allocated_percent(allocated_bytes: u64, virtual_disk_size: u64) -> f64
+{
+	if virtual_disk_size == 0
 	{
-		ensure!(false, "Could not find parent with any of the relative path '{}', the volume path '{}' or the absolute path '{}'.",
-			locator.relative_path, locator.volume_path, locator.absolute_win32_path);
-		return Ok(String::new());
+		return 0.0;
 	}
+	return allocated_bytes as f64 * 100.0 / virtual_disk_size as f64;
+}
+
+fn bytes_to_gib(bytes: u64) -> f64
+{
+	return bytes as f64 / u64::pow(1024, 3) as f64;
+}
+
+fn print_json_error(error: &VhdxError)
+{
+	eprintln!("{}", serde_json::json!({"error": error.to_string(), "code": error.kind(), "exit_code": error.exit_code(), "location": error.offset()}));
 }
 
-fn main() -> Result<(), Error>
+fn main()
 {
 	let args: Vec<String> = env::args().collect();
+	let json_errors = args.iter().any(|x| x == "--json-errors" || x == "--annotate" || x == "-j" || x == "--json");
+	let verify_only = args.iter().any(|x| x == "--verify" || x == "--verify-chain");
 
+	if let Err(error) = run(args)
+	{
+		if json_errors
+		{
+			print_json_error(&error);
+		}
+		else if verify_only
+		{
+			eprintln!("{}", error);
+		}
+		else
+		{
+			eprintln!("Error: {}", error);
+		}
+		process::exit(error.exit_code());
+	}
+}
+
+fn run(args: Vec<String>) -> Result<(), VhdxError>
+{
 	if args.len() == 1
 	{
 		print_help();
@@ -101,10 +979,47 @@ fn main() -> Result<(), Error>
 	let mut file_path:String = String::from("");
 	let mut follow_chain = false;
 	let mut print_blocks = false;
-	let mut disk_type = VhdType::Fixed;
+	let mut print_bitmaps = false;
+	let mut print_summary = false;
+	let mut print_stats = false;
+	let mut compact = false;
+	let mut annotate_layout = false;
+	let mut json_output = false;
+	let mut yaml_output = false;
+	let mut diff_path: Option<String> = None;
+	let mut diff_data = false;
+	let mut extract_path: Option<String> = None;
+	let mut output_dir: Option<String> = None;
+	let mut force = false;
+	let mut csv_path: Option<String> = None;
+	let mut verify_only = false;
+	let mut verify_chain_only = false;
+	let mut best_effort = false;
+	let mut metadata_only = false;
+	let mut quiet = false;
+	let mut fix_only = false;
+	let mut raw_header = false;
+	let mut decimal = false;
+	let mut dump_region_id: Option<Uuid> = None;
+	let mut dump_path: Option<String> = None;
+	let mut compare_path: Option<String> = None;
+	let mut read_lba: Option<u64> = None;
+	let mut translate: Option<u64> = None;
+	let mut read_count: u64 = 1;
 	let mut parent_locator: Option<ParentLocator> = None;
+	let mut chain_members: u64 = 0;
+	let mut chain_file_bytes: u64 = 0;
+	let mut chain_allocated_bytes: u64 = 0;
+	let mut chain_virtual_size: Option<u64> = None;
+	let mut max_depth: u64 = DEFAULT_MAX_CHAIN_DEPTH;
+	let mut parent_override: Option<String> = None;
+	let mut strict = false;
+	let mut use_header: Option<HeaderCopy> = None;
+	let mut visited_paths = HashSet::<PathBuf>::new();
+	let mut visited_ids = HashSet::<Uuid>::new();
 
-	for arg in args
+	let mut arg_iter = args.into_iter().skip(1);
+	while let Some(arg) = arg_iter.next()
 	{
 		if arg == "-h" || arg == "--help"
 		{
@@ -116,11 +1031,191 @@ fn main() -> Result<(), Error>
 			follow_chain = true;
 			continue;
 		}
+		else if arg == "--strict"
+		{
+			strict = true;
+			continue;
+		}
+		else if arg == "--max-depth"
+		{
+			let value = arg_iter.next().and_then(|x| x.parse().ok());
+			ensure!(value.is_some(), "--max-depth requires a number.");
+			max_depth = value.unwrap();
+			continue;
+		}
+		else if arg == "--use-header"
+		{
+			use_header = match arg_iter.next().as_deref()
+			{
+				Some("1") => Some(HeaderCopy::First),
+				Some("2") => Some(HeaderCopy::Second),
+				_ => None,
+			};
+			ensure!(use_header.is_some(), "--use-header requires 1 or 2.");
+			continue;
+		}
+		else if arg == "--parent"
+		{
+			parent_override = arg_iter.next();
+			ensure!(parent_override.is_some(), "--parent requires a file name.");
+			continue;
+		}
 		else if arg == "-b" || arg == "--blocks"
 		{
 			print_blocks = true;
 			continue;
 		}
+		else if arg == "--compact"
+		{
+			compact = true;
+			continue;
+		}
+		else if arg == "--summary"
+		{
+			print_summary = true;
+			continue;
+		}
+		else if arg == "--stats"
+		{
+			print_stats = true;
+			continue;
+		}
+		else if arg == "--bitmaps"
+		{
+			print_bitmaps = true;
+			continue;
+		}
+		else if arg == "-j" || arg == "--json"
+		{
+			json_output = true;
+			continue;
+		}
+		else if arg == "--yaml"
+		{
+			json_output = true;
+			yaml_output = true;
+			continue;
+		}
+		else if arg == "--json-errors"
+		{
+			continue;
+		}
+		else if arg == "--annotate"
+		{
+			annotate_layout = true;
+			continue;
+		}
+		else if arg == "--diff-blocks"
+		{
+			diff_path = arg_iter.next();
+			ensure!(diff_path.is_some(), "--diff-blocks requires a file name.");
+			continue;
+		}
+		else if arg == "--compare"
+		{
+			compare_path = arg_iter.next();
+			ensure!(compare_path.is_some(), "--compare requires a file name.");
+			continue;
+		}
+		else if arg == "--diff-data"
+		{
+			diff_data = true;
+			continue;
+		}
+		else if arg == "-q" || arg == "--quiet"
+		{
+			quiet = true;
+			continue;
+		}
+		else if arg == "--metadata-only"
+		{
+			metadata_only = true;
+			continue;
+		}
+		else if arg == "--read-lba"
+		{
+			read_lba = arg_iter.next().and_then(|x| x.parse().ok());
+			ensure!(read_lba.is_some(), "--read-lba requires a sector number.");
+			continue;
+		}
+		else if arg == "--translate"
+		{
+			translate = arg_iter.next().and_then(|x| parse_number(&x));
+			ensure!(translate.is_some(), "--translate requires a byte offset.");
+			continue;
+		}
+		else if arg == "--count"
+		{
+			let value = arg_iter.next().and_then(|x| x.parse().ok());
+			ensure!(value.is_some(), "--count requires a number.");
+			read_count = value.unwrap();
+			continue;
+		}
+		else if arg == "--fix-checksums"
+		{
+			fix_only = true;
+			continue;
+		}
+		else if arg == "--decimal"
+		{
+			decimal = true;
+			continue;
+		}
+		else if arg == "--raw-header"
+		{
+			raw_header = true;
+			continue;
+		}
+		else if arg == "--dump-region"
+		{
+			dump_region_id = arg_iter.next().and_then(|x| Uuid::parse_str(&x).ok());
+			ensure!(dump_region_id.is_some(), "--dump-region requires a region GUID.");
+			continue;
+		}
+		else if arg == "--dump-to"
+		{
+			dump_path = arg_iter.next();
+			ensure!(dump_path.is_some(), "--dump-to requires a file name.");
+			continue;
+		}
+		else if arg == "--verify"
+		{
+			verify_only = true;
+			continue;
+		}
+		else if arg == "--best-effort"
+		{
+			best_effort = true;
+			continue;
+		}
+		else if arg == "--verify-chain"
+		{
+			verify_chain_only = true;
+			continue;
+		}
+		else if arg == "--blocks-csv"
+		{
+			csv_path = arg_iter.next();
+			ensure!(csv_path.is_some(), "--blocks-csv requires a file name.");
+			continue;
+		}
+		else if arg == "--extract"
+		{
+			extract_path = arg_iter.next();
+			ensure!(extract_path.is_some(), "--extract requires a file name.");
+			continue;
+		}
+		else if arg == "--output-dir"
+		{
+			output_dir = arg_iter.next();
+			ensure!(output_dir.is_some(), "--output-dir requires a directory name.");
+			continue;
+		}
+		else if arg == "--force"
+		{
+			force = true;
+			continue;
+		}
 		else if arg.starts_with("-")
 		{
 			print_help();
@@ -128,191 +1223,262 @@ fn main() -> Result<(), Error>
 		}
 		else
 		{
-			file_path = String::from(arg);
+			file_path = arg;
 			continue;
 		}
 	}
 
-	loop
+	if let Some(other_path) = compare_path
 	{
-		println!("Reading VHDX file {}.", &file_path);
-
-		let mut vhdx_file = File::open(&file_path)?;
+		if !print_compare(&file_path, &other_path)?
+		{
+			process::exit(EXIT_OTHER);
+		}
+		return Ok(());
+	}
+	if let Some(other_path) = diff_path
+	{
+		return print_block_diff(&file_path, &other_path, diff_data);
+	}
+	let verbose = !json_output && !quiet;
+	ensure!(parent_override.is_none() || follow_chain, "--parent can only be used with --follow.");
+	if fix_only
+	{
+		return fix_checksums(&file_path);
+	}
+	if raw_header
+	{
+		return print_raw_headers(&file_path);
+	}
+	if best_effort
+	{
+		return print_best_effort(&file_path, decimal);
+	}
+	if verify_chain_only
+	{
+		return verify_chain(&file_path, max_depth);
+	}
+	if verify_only
+	{
+		let vhdx = VhdxFile::open(&file_path)?;
+		if quiet
+		{
+			print_quiet_findings(&file_path, &collect_findings(&vhdx), decimal);
+		}
+		else
+		{
+			println!("OK");
+		}
+		return Ok(());
+	}
+	if !file_header::is_vhdx_file(&mut File::open(&file_path)?)?
+	{
+		return print_vhd_report(&file_path, json_output, yaml_output, quiet, decimal);
+	}
+	if let Some(out_path) = csv_path
+	{
+		return write_blocks_csv(&file_path, &out_path);
+	}
+	if let Some(region_id) = dump_region_id
+	{
+		return dump_region(&file_path, &region_id, dump_path.as_deref(), decimal);
+	}
+	if let Some(virtual_offset) = translate
+	{
+		return translate_offset(&file_path, virtual_offset, decimal);
+	}
+	if let Some(lba) = read_lba
+	{
+		return read_lba_range(&file_path, lba, read_count, max_depth);
+	}
+	if let Some(out_path) = extract_path
+	{
+		return extract_image(&file_path, &out_path);
+	}
+	if let Some(output_dir) = output_dir
+	{
+		return extract_image(&file_path, &output_dir_path(&file_path, &output_dir, force)?);
+	}
 
-		let header = file_header::read_file_header(&mut vhdx_file)?;
-		let (vhdx_offset, vhdx_header) = vhd_header::read_vhdx_header(&mut vhdx_file)?;
-		let region_table = region::read_region(&mut vhdx_file)?;
-		let metadata_region = &region_table.entries.iter().find(|x| x.region_type == RegionType::Metadata).unwrap();
-		let bat_region = &region_table.entries.iter().find(|x| x.region_type == RegionType::BAT).unwrap();
-		let (metadata_table, metadata) = metadata::read_metadata(&mut vhdx_file, metadata_region)?;
-		let (payload_blocks,sector_blocks) = block::read_bat(&mut vhdx_file, bat_region, &metadata, parent_locator.is_some())?;
+	let mut json_reports = Vec::<serde_json::Value>::new();
 
-		if parent_locator.is_some()
+	loop
+	{
+		if !annotate_layout && verbose
 		{
-			disk_type = VhdType::Differencing;
-			let parent = parent_locator.unwrap();
-			parent_locator = None;
-			
-			if parent.parent_linkage == vhdx_header.data_write_id
-			{
-				println!("Parent linkage Data Write GUID {} identified by parent_linkage value.", vhdx_header.data_write_id);
-			}
-			else if parent.parent_linkage2 == vhdx_header.data_write_id
-			{
-				println!("Parent linkage Data Write GUID {} identified by parent_linkage2 value.", vhdx_header.data_write_id);
-			}
-			else
-			{
-				ensure!(false, "Parent disk located at {} has Data Write GUID {} but metadata expected an ID of either {} or {}.",
-					&file_path, vhdx_header.data_write_id, parent.parent_linkage, parent.parent_linkage2);
-			}
+			println!("Reading VHDX file {}.", &file_path);
 		}
 
-		if payload_blocks.iter().any(|x| x.state == PayloadBlockState::NotPresent || x.state == PayloadBlockState::PartiallyPresent)
+		ensure!(chain_members < max_depth, "Parent chain is longer than the maximum depth of {}.", max_depth);
+		// A forced header copy only applies to the file named on the command line, not to its parents.
+		let forced_header = use_header.take();
+		let mut vhdx = match (forced_header, metadata_only)
+		{
+			(Some(header_copy), _) => VhdxFile::open_with_header(&file_path, !metadata_only, header_copy)?,
+			(None, true) => VhdxFile::open_metadata_only(&file_path)?,
+			(None, false) => VhdxFile::open(&file_path)?,
+		};
+		ensure!(visited_paths.insert(Path::new(&file_path).canonicalize()?) && visited_ids.insert(vhdx.vhdx_header.data_write_id),
+			"Parent chain contains a cycle at {}.", &file_path);
+		let mut findings = collect_findings(&vhdx);
+		if let Some(header_copy) = forced_header
 		{
-			disk_type = VhdType::Dynamic;
+			findings.insert(0, Finding::new(Severity::Warning, "vhdx-header-forced",
+				format!("The {} VHDX header copy was forced with --use-header, the file is read as that header describes.", header_copy),
+				Some(vhdx.vhdx_header_offset as u64)));
 		}
 
-		println!("VHDX file {} is {}.", &file_path, disk_type);
-		println!("File signature is created by {}.", header.creator);
-		println!();
-		println!("VHDX header at 0x{:X} says:", vhdx_offset);
-		println!("	Checksum is				0x{:X}.", vhdx_header.checksum);
-		println!("	Current sequence number is		0x{:X}.", vhdx_header.sequence_number);
-		println!("	File Write GUID is			{}.", vhdx_header.file_write_id);
-		println!("	Data Write GUID is			{}.", vhdx_header.data_write_id);
-		println!("	Log GUID is				{}.", vhdx_header.log_id);
-		println!("	Log version is				{}.", vhdx_header.log_version);
-		println!("	Version is				{}.", vhdx_header.version);
-		println!("	Log length is				0x{:X}.", vhdx_header.log_length);
-		println!("	Log Offset is				0x{:X}.", vhdx_header.log_offset);
-		println!();
+		if annotate_layout
+		{
+			let annotations = annotate::annotate_file(&mut vhdx.file, vhdx.vhdx_header_offset, &vhdx.region_table, &vhdx.metadata_table,
+				&vhdx.metadata, vhdx.payload_blocks.len() + vhdx.sector_blocks.len())?;
+			println!("{}", annotate::annotations_to_json(&annotations)?);
+			return Ok(());
+		}
 
-		println!("Region table contains:");
-		println!("	Checksum is				0x{:X}.", region_table.checksum);
-		println!("	Entry count is				0x{:X}.", region_table.entry_count);
-		println!("	Regions:");
-		for entry in region_table.entries
+		if let Some(parent) = parent_locator.take()
 		{
-			match entry.region_type
+			let data_write_id = vhdx.vhdx_header.data_write_id;
+			
+			match chain::check_parent_linkage(&parent, Path::new(&file_path), data_write_id)?
 			{
-				RegionType::BAT => println!("		Type:				Block Allocation Table"),
-				RegionType::Metadata => println!("		Type:				Metadata"),
-				RegionType::Unknown => println!("		Type:			Unknown"),
+				ParentLinkageMatch::Linkage1 if verbose =>
+					println!("Parent linkage Data Write GUID {} identified by parent_linkage value.", data_write_id),
+				ParentLinkageMatch::Linkage2 if verbose =>
+					println!("Parent linkage Data Write GUID {} identified by parent_linkage2 value.", data_write_id),
+				_ => {},
 			}
-			println!("		Region ID:			{}.", entry.object_id);
-			println!("		Region offset:			0x{:X}.", entry.object_offset);
-			println!("		Region length:			0x{:X}.", entry.object_length);
-			println!("		Required:			{}.", entry.required);
-			println!();
 		}
 
-		if print_blocks
+		if metadata_only
 		{
-			println!("Payload blocks:");
-			let mut block_index: u64 = 0;
-			for payload in payload_blocks
+			chain_members += 1;
+			if json_output
 			{
-				println!("	Block {} at offset {}MiB is {}.", block_index, payload.file_offset_mb, payload.state);
-				block_index += 1;
+				json_reports.push(serde_json::json!({
+					"file_path": &file_path,
+					"disk_type": vhdx.disk_type().to_string(),
+					"metadata": serde_json::to_value(&vhdx.metadata)?,
+				}));
 			}
-			println!();
-
-			println!("Sector blocks:");
-			block_index = 0;
-			for sector in sector_blocks
+			else if quiet
 			{
-				println!("	Block {} at offset {}MiB is {}.", block_index, sector.file_offset_mb, sector.state);
-				block_index += 1;
+				print_quiet_findings(&file_path, &findings, decimal);
 			}
-			println!();
-		}
-
-		println!("Metadata table contains:");
-		println!("	Entry count is:				0x{:X}.", metadata_table.entry_count);
-		println!("	Metadata entries:");
-		for entry in metadata_table.entries
-		{
-			println!("		Metadata type:			{}.", match entry.metadata_type
-				{
-					MetadataType::FileParameters => "File Parameters",
-					MetadataType::VirtualDiskSize => "Virtual Disk Size",
-					MetadataType::VirtualDiskId => "Virtual Disk ID",
-					MetadataType::LogicalSectorSize => "Logical Sector Size",
-					MetadataType::PhysicalSectorSize => "Physical Sector Size",
-					MetadataType::ParentLocator => "Parent Locator",
-					MetadataType::Unknown => "Unknown",
-				}
-			);
-			println!("		Metadata ID:			{}.", entry.object_id);
-			println!("		Metadata offset:		0x{:X}.", entry.object_offset);
-			println!("		Metadata length:		0x{:X}.", entry.object_length);
-			println!("		Is User:			{}.", entry.is_user);
-			println!("		Is Virtual Disk:		{}.", entry.is_virtual_disk);
-			println!("		Is Required:			{}.", entry.is_required);
-			println!();
-		}
-
-		println!("Metadata contains:");
-		println!("	Block size is:				0x{:X}.", metadata.file_parameters.block_size);
-		println!("	Leave block allocated:			{}.", metadata.file_parameters.leave_block_allocated);
-		println!("	Has parent:				{}.", metadata.file_parameters.has_parent);
-		println!("	Virtual disk size:			0x{:X}.", metadata.virtual_disk_size);
-		println!("	Virtual disk size on disk:		0x{:X}.", vhdx_file.metadata()?.len());
-		println!("	Virtual disk ID:			{}.", metadata.virtual_disk_id);
-		println!("	Logical sector size:			0x{:X}.", metadata.logical_sector_size);
-		println!("	Physical sector size:			0x{:X}.", metadata.physical_sector_size);
-		if metadata.parent_locator.is_some()
-		{
-			let locator = &metadata.parent_locator_dict.as_ref().unwrap();
-			println!("	Parent locator contains:");
-			println!("		Locator type:			{}.", match locator.locator_type
-				{
-					ParentLocatorType::Vhdx => "VHDX",
-					ParentLocatorType::Unknown => "Unknown",
-				}
-			);
-			println!("		Locator type ID:		{}.", locator.locator_type_id);
-			println!("		Locator key/value count:	0x{:X}.", locator.key_value_count);
-			for locatorkv in &locator.entries
+			else
 			{
-				println!("			Key offset:		0x{:X}.", locatorkv.key_offset);
-				println!("			Key length:		0x{:X}.", locatorkv.key_length);
-				println!("			Key:			{}.", locatorkv.key);
-				println!();
-				println!("			Value offset:		0x{:X}.", locatorkv.value_offset);
-				println!("			Value length:		0x{:X}.", locatorkv.value_length);
-				println!("			Value:			{}.", locatorkv.value);
-				println!();
+				print_metadata_only(&file_path, &vhdx, decimal);
 			}
 		}
 		else
 		{
-			println!();
-			println!("	Parent locator absent, disk is the head of its chain.");
-			println!();
+			let file_length = vhdx.file.metadata()?.len();
+			let allocated_bytes = block::allocated_payload_bytes(&vhdx.payload_blocks, vhdx.metadata.file_parameters.block_size);
+			chain_members += 1;
+			chain_file_bytes += file_length;
+			chain_allocated_bytes += allocated_bytes;
+			chain_virtual_size.get_or_insert(vhdx.metadata.virtual_disk_size);
+
+			let sector_bitmaps = if print_bitmaps {Some(count_sector_bitmaps(&mut vhdx)?)} else {None};
+
+			let fragmentation = if print_stats {Some(block::fragmentation_stats(&vhdx.payload_blocks, vhdx.metadata.file_parameters.block_size))} else {None};
+
+			let report = Report{file_path: &file_path, vhdx: &vhdx, file_length, allocated_bytes, sector_bitmaps,
+				fragmentation, findings: &findings, decimal, compact};
+			if json_output
+			{
+				json_reports.push(json_report(&report, print_blocks, print_summary)?);
+			}
+			else if quiet
+			{
+				print_quiet_findings(&file_path, &findings, decimal);
+			}
+			else
+			{
+				print_report(&report, print_blocks, print_summary);
+			}
 		}
 
-		if follow_chain && metadata.parent_locator.is_some()
+		match vhdx.metadata.parent_locator.as_ref().filter(|_| follow_chain).map(|x| x.locator_type.clone())
 		{
-			match metadata.parent_locator.as_ref().unwrap().locator_type
+			Some(ParentLocatorType::Vhdx) => 
 			{
-				ParentLocatorType::Vhdx => 
+				let locator = vhdx.metadata.parent_locator.unwrap();
+				let located = chain::calc_parent_path(&locator, Path::new(OsStr::new(&file_path)));
+				// The path given on the command line replaces the locator for the first link only, its linkage is still checked.
+				file_path = match parent_override.take()
 				{
-					parent_locator = metadata.parent_locator;
-					file_path = calc_parent_path(parent_locator.as_ref().unwrap(), &Path::new(OsStr::new(&file_path)))?;
-				},
-				ParentLocatorType::Unknown => 
+					Some(parent_path) =>
+					{
+						if let (true, Ok((located_path, source))) = (verbose, &located)
+						{
+							println!("Using parent {} from --parent instead of {} located from {}.", parent_path, located_path, source);
+						}
+						parent_path
+					},
+					None =>
+					{
+						// A missing parent is often expected, such as a child copied off on its own, so by default the walk just ends there.
+						let (parent_path, source) = match located
+						{
+							Ok(located) => located,
+							Err(error) if !strict =>
+							{
+								let message = format!("Parent not found, stopping chain walk: {}", error);
+								if verbose
+								{
+									println!("{}", message);
+								}
+								else if quiet
+								{
+									print_quiet_findings(&file_path, &[Finding::new(Severity::Warning, "parent-not-found", message, None)], decimal);
+								}
+								break;
+							},
+							Err(error) => return Err(error),
+						};
+						if verbose
+						{
+							println!("Located parent from {} '{}'.", source, source.locator_value(&locator));
+						}
+						parent_path
+					},
+				};
+				parent_locator = Some(locator);
+			},
+			Some(ParentLocatorType::Unknown) => 
+			{
+				if verbose
 				{
 					println!("Could not follow locator for unknown parent type {}.",
-						&metadata.parent_locator_dict.unwrap().locator_type_id);
-				},
-			}
+						&vhdx.metadata.parent_locator_dict.unwrap().locator_type_id);
+				}
+				break;
+			},
+			None => break,
 		}
-		else
+	}
+
+	if json_output
+	{
+		let mut output = serde_json::json!({"format_version": JSON_FORMAT_VERSION, "tool_version": env!("CARGO_PKG_VERSION"),
+			"files": json_reports});
+		if follow_chain && !metadata_only
 		{
-			break;
+			output["chain"] = serde_json::json!({
+				"members": chain_members,
+				"file_bytes": chain_file_bytes,
+				"allocated_bytes": chain_allocated_bytes,
+				"virtual_disk_size": chain_virtual_size.unwrap_or(0),
+			});
 		}
+		print_structured(&output, yaml_output)?;
+	}
+	else if follow_chain && chain_members > 1 && !metadata_only && !quiet
+	{
+		println!("Chain consumes {:.1} GiB across {} files for a {:.1} GiB virtual disk ({:.1} GiB in allocated payload blocks).",
+			bytes_to_gib(chain_file_bytes), chain_members, bytes_to_gib(chain_virtual_size.unwrap_or(0)),
+			bytes_to_gib(chain_allocated_bytes));
 	}
 
 	return Ok(());
@@ -48,5 +48,81 @@ macro_rules! divide_signed
 	}
 }
 
+divide_unsigned!(u32);
 divide_unsigned!(u64);
+divide_unsigned!(usize);
+divide_signed!(i32);
 divide_signed!(i64);
+
+pub fn format_offset(value: u64, decimal: bool) -> String
+{
+	if decimal
+	{
+		return format!("0x{:X} ({})", value, value);
+	}
+	return format!("0x{:X}", value);
+}
+
+const SIZE_UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+pub fn format_size(bytes: u64) -> String
+{
+	if bytes < 1024
+	{
+		return format!("{} bytes (0x{:X} bytes)", bytes, bytes);
+	}
+
+	let mut value = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while value >= 1024.0 && unit + 1 < SIZE_UNITS.len()
+	{
+		value /= 1024.0;
+		unit += 1;
+	}
+	return format!("{:.1} {} (0x{:X} bytes)", value, SIZE_UNITS[unit], bytes);
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn exact_division()
+	{
+		assert_eq!(u32::ceiling_divide(64_u32, 32), 2);
+		assert_eq!(u32::floor_divide(64_u32, 32), 2);
+		assert_eq!(usize::ceiling_divide(4096_usize, 512), 8);
+		assert_eq!(u64::floor_divide(u64::pow(1024, 3), u64::pow(1024, 2)), 1024);
+		assert_eq!(i32::ceiling_divide(-64_i32, 32), -2);
+		assert_eq!(i32::floor_divide(-64_i32, 32), -2);
+	}
+
+	#[test]
+	fn remainder_rounding()
+	{
+		assert_eq!(u32::ceiling_divide(65_u32, 32), 3);
+		assert_eq!(u32::floor_divide(65_u32, 32), 2);
+		assert_eq!(usize::ceiling_divide(1_usize, 512), 1);
+		assert_eq!(u64::ceiling_divide(u64::MAX, 2), u64::MAX / 2 + 1);
+
+		// Signed values round towards positive or negative infinity rather than towards zero.
+		assert_eq!(i32::ceiling_divide(7_i32, 2), 4);
+		assert_eq!(i32::floor_divide(7_i32, 2), 3);
+		assert_eq!(i32::ceiling_divide(-7_i32, 2), -3);
+		assert_eq!(i32::floor_divide(-7_i32, 2), -4);
+		assert_eq!(i64::ceiling_divide(7_i64, -2), -3);
+		assert_eq!(i64::floor_divide(7_i64, -2), -4);
+	}
+
+	#[test]
+	fn zero_numerator()
+	{
+		assert_eq!(u32::ceiling_divide(0_u32, 32), 0);
+		assert_eq!(u32::floor_divide(0_u32, 32), 0);
+		assert_eq!(usize::ceiling_divide(0_usize, 512), 0);
+		assert_eq!(u64::ceiling_divide(0_u64, 1), 0);
+		assert_eq!(i32::ceiling_divide(0_i32, -2), 0);
+		assert_eq!(i32::floor_divide(0_i32, -2), 0);
+	}
+}
@@ -2,15 +2,20 @@
 // Copyright (c) Nick Moss.
 
 use std::{
-	fs::File,
-	io::{Seek, Read, SeekFrom},
+	fmt,
+	io::{Seek, Read, SeekFrom, Write},
 };
 
-use failure::{ensure,Fallible};
+use byteorder::{LittleEndian,WriteBytesExt};
+use serde::Serialize;
 use uuid::{Uuid,uuid,};
 
+use crate::error::{Fallible,VhdxError};
+use crate::{ensure,format_err};
+use crate::json;
+use crate::maths::{format_offset,format_size};
 use crate::region::{RegionType,RegionTableEntry,};
-use crate::reader::ReadValue;
+use crate::reader::{read_into,ReadValue};
 
 const METADATA_HEADER_LEN: usize = 0x20;
 const METADATA_HEADER_SIG: [u8; METADATA_HEADER_SIG_LEN] = [0x6d, 0x65, 0x74, 0x61, 0x64, 0x61, 0x74, 0x61];
@@ -18,6 +23,10 @@ const METADATA_HEADER_SIG_LEN: usize = 0x8;
 const METADATA_HEADER_RESERVED_1_LEN: usize = 0x2;
 
 const METADATA_ENTRY_LEN: usize = 0x20;
+const METADATA_TABLE_LEN: usize = 0x10000;
+const MAX_METADATA_ENTRIES: usize = (METADATA_TABLE_LEN - METADATA_HEADER_LEN) / METADATA_ENTRY_LEN;
+const MAX_PARENT_LOCATOR_ENTRIES: u16 = 256;
+const METADATA_REGION_ALIGNMENT: u32 = 0x10000;
 
 const METADATA_PARENT_LOCATOR_HEADER_LEN: usize = 0x14;
 const METADATA_PARENT_LOCATOR_ENTRY_LEN: usize = 0xc;
@@ -35,6 +44,14 @@ const METADATA_ENTRY_IS_REQUIRED_FLAG:u32 = 0b00000100;
 const METADATA_LEAVE_ALLOCATED_FLAG:u32 = 0b00000001;
 const METADATA_HAS_PARENT_FLAG:u32 = 0b00000010;
 const METADATA_PARENT_LOCATOR_HEADER_RESERVED_1_LEN: usize = 0x2;
+const VALID_SECTOR_SIZES: [u32; 2] = [512, 4096];
+const DEFAULT_PHYSICAL_SECTOR_SIZE: u32 = 512;
+const MIN_BLOCK_SIZE: u32 = u32::pow(2, 20);
+const MAX_BLOCK_SIZE: u32 = u32::pow(2, 28);
+
+const REQUIRED_METADATA_TYPES: [MetadataType; 5] = [MetadataType::FileParameters, MetadataType::VirtualDiskSize,
+	MetadataType::LogicalSectorSize, MetadataType::PhysicalSectorSize, MetadataType::VirtualDiskId];
+
 const METADATA_PARENT_LOCATOR_VHDX: Uuid = uuid!("B04AEFB7-D19E-4A81-B789-25B8E9445913");
 
 const PARENT_LOCATOR_LINKAGE1_KEY: &str = "parent_linkage";
@@ -42,8 +59,10 @@ const PARENT_LOCATOR_LINKAGE2_KEY: &str = "parent_linkage2";
 const PARENT_LOCATOR_RELATIVE_PATH_KEY: &str = "relative_path";
 const PARENT_LOCATOR_VOLUME_PATH_KEY: &str = "volume_path";
 const PARENT_LOCATOR_ABSOLUTE_PATH_KEY: &str = "absolute_win32_path";
+const PARENT_LOCATOR_KNOWN_KEYS: [&str; 5] = [PARENT_LOCATOR_LINKAGE1_KEY, PARENT_LOCATOR_LINKAGE2_KEY, PARENT_LOCATOR_RELATIVE_PATH_KEY,
+	PARENT_LOCATOR_VOLUME_PATH_KEY, PARENT_LOCATOR_ABSOLUTE_PATH_KEY];
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub enum MetadataType
 {
 	#[default]
@@ -56,12 +75,28 @@ pub enum MetadataType
 	ParentLocator,
 }
 
-#[derive(PartialEq, Default)]
+impl fmt::Display for MetadataType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			MetadataType::FileParameters => write!(f, "File Parameters"),
+			MetadataType::VirtualDiskSize => write!(f, "Virtual Disk Size"),
+			MetadataType::VirtualDiskId => write!(f, "Virtual Disk ID"),
+			MetadataType::LogicalSectorSize => write!(f, "Logical Sector Size"),
+			MetadataType::PhysicalSectorSize => write!(f, "Physical Sector Size"),
+			MetadataType::ParentLocator => write!(f, "Parent Locator"),
+			MetadataType::Unknown => write!(f, "Unknown"),
+		}
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct MetadataTableEntry
 {
 	pub metadata_type: MetadataType,
 	pub object_id: Uuid,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub object_offset: u32,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub object_length: u32,
 	pub is_user: bool,
 	pub is_virtual_disk: bool,
@@ -70,30 +105,40 @@ pub struct MetadataTableEntry
 
 impl MetadataTableEntry
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), offset: u64) -> Fallible<Self>
 	{
 		let mut result = MetadataTableEntry::default();
 		
-		result.object_id.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read metadata table entry object ID Uuid: {:?}", error)});
-		result.object_offset.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read metadata table entry object offset u64: {:?}", error)});
-		result.object_length.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read metadata table entry object length u32: {:?}", error)});
+		result.object_id.read_value(data).map_err(|error| VhdxError::read("metadata table entry object ID Uuid", offset, error))?;
+		result.object_offset.read_value(data).map_err(|error| VhdxError::read("metadata table entry object offset u32", offset + 0x10, error))?;
+		result.object_length.read_value(data).map_err(|error| VhdxError::read("metadata table entry object length u32", offset + 0x14, error))?;
 
 		let mut flags:u32 = 0;
-		flags.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read file parameter flags u32: {:?}", error)});
+		flags.read_value(data).map_err(|error| VhdxError::read("metadata table entry flags u32", offset + 0x18, error))?;
 
 		result.is_user = flags & METADATA_ENTRY_IS_USER_FLAG != 0;
 		result.is_virtual_disk = flags & METADATA_ENTRY_IS_VIRTUAL_DISK_FLAG != 0;
 		result.is_required = flags & METADATA_ENTRY_IS_REQUIRED_FLAG != 0;
 		
-		return result;
+		return Ok(result);
+	}
+
+	pub fn write_to(self: &Self, out: &mut impl Write) -> Fallible<()>
+	{
+		let flags = (self.is_user as u32 * METADATA_ENTRY_IS_USER_FLAG) | (self.is_virtual_disk as u32 * METADATA_ENTRY_IS_VIRTUAL_DISK_FLAG) |
+			(self.is_required as u32 * METADATA_ENTRY_IS_REQUIRED_FLAG);
+
+		out.write_all(&self.object_id.to_bytes_le())?;
+		out.write_u32::<LittleEndian>(self.object_offset)?;
+		out.write_u32::<LittleEndian>(self.object_length)?;
+		out.write_u32::<LittleEndian>(flags)?;
+		out.write_u32::<LittleEndian>(0)?;
+
+		return Ok(());
 	}
 }
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct MetadataTable
 {
 	pub entry_count: u16,
@@ -102,26 +147,79 @@ pub struct MetadataTable
 
 impl MetadataTable
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), offset: u64) -> Fallible<Self>
 	{
 		let mut result = MetadataTable::default();
 		
-		result.entry_count.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read metadata table entry count u32: {:?}", error)});
+		result.entry_count.read_value(data).map_err(|error| VhdxError::read("metadata table entry count u16", offset + 0xA, error))?;
+		// Counts come straight from the file, so they are bounded before anything is allocated for them.
+		ensure!(result.entry_count as usize <= MAX_METADATA_ENTRIES, InvalidMetadata,
+			"Metadata table entry count {} exceeds the specified maximum {}.", result.entry_count, MAX_METADATA_ENTRIES);
 		result.entries.reserve(result.entry_count as usize);
 		
-		return result;
+		return Ok(result);
 	}
 
 	pub fn add_entry(self: &mut Self, entry: MetadataTableEntry) -> ()
 	{
 		self.entries.push(entry);
 	}
+
+	pub fn free_space(self: &Self, region_length: u64) -> u64
+	{
+		// The space after the last item is what a writer could still use, the table itself always takes the first 64 KiB.
+		let used = self.entries.iter().map(|x| x.object_offset as u64 + x.object_length as u64).max().unwrap_or(0).max(METADATA_TABLE_LEN as u64);
+		return region_length.saturating_sub(used);
+	}
+
+	pub fn write_to(self: &Self, out: &mut (impl Write + Seek)) -> Fallible<()>
+	{
+		ensure!(self.entries.len() <= MAX_METADATA_ENTRIES, "Metadata table entry count exceeds the specified maximum {}.", MAX_METADATA_ENTRIES);
+
+		// Only the 64 KiB table is written, the item values it points to are left to the caller.
+		let mut buffer: Vec<u8> = Vec::with_capacity(METADATA_TABLE_LEN);
+		buffer.write_all(&METADATA_HEADER_SIG)?;
+		buffer.write_all(&[0;METADATA_HEADER_RESERVED_1_LEN])?;
+		buffer.write_u16::<LittleEndian>(self.entries.len() as u16)?;
+		buffer.resize(METADATA_HEADER_LEN, 0);
+		for entry in &self.entries
+		{
+			entry.write_to(&mut buffer)?;
+		}
+		buffer.resize(METADATA_TABLE_LEN, 0);
+		out.write_all(&buffer)?;
+
+		return Ok(());
+	}
 }
 
-#[derive(PartialEq, Default)]
+impl fmt::Display for MetadataTable {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "	Entry count is:				0x{:X}.", self.entry_count)?;
+		writeln!(f, "	Metadata entries:")?;
+		for entry in &self.entries
+		{
+			match entry.metadata_type
+			{
+				MetadataType::Unknown => writeln!(f, "		Metadata type:			Unknown metadata (possibly vendor extension {}).", entry.object_id)?,
+				_ => writeln!(f, "		Metadata type:			{}.", entry.metadata_type)?,
+			}
+			writeln!(f, "		Metadata ID:			{}.", entry.object_id)?;
+			writeln!(f, "		Metadata offset:		{}.", format_offset(entry.object_offset as u64, f.alternate()))?;
+			writeln!(f, "		Metadata length:		{}.", format_offset(entry.object_length as u64, f.alternate()))?;
+			writeln!(f, "		Is User:			{}.", entry.is_user)?;
+			writeln!(f, "		Is Virtual Disk:		{}.", entry.is_virtual_disk)?;
+			writeln!(f, "		Is Required:			{}.", entry.is_required)?;
+			writeln!(f)?;
+		}
+		return Ok(());
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct FileParameters
 {
+	#[serde(serialize_with = "json::dec_hex")]
 	pub block_size: u32,
 	pub leave_block_allocated: bool,
 	pub has_parent: bool,
@@ -129,30 +227,32 @@ pub struct FileParameters
 
 impl FileParameters
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), offset: u64) -> Fallible<Self>
 	{
 		let mut result = FileParameters::default();
 		
-		result.block_size.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read file parameter block size u32: {:?}", error)});
+		result.block_size.read_value(data).map_err(|error| VhdxError::read("file parameter block size u32", offset, error))?;
 
 		let mut flags:u32 = 0;
-		flags.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read file parameter flags u32: {:?}", error)});
+		flags.read_value(data).map_err(|error| VhdxError::read("file parameter flags u32", offset + 0x4, error))?;
 
 		result.leave_block_allocated = flags & METADATA_LEAVE_ALLOCATED_FLAG != 0;
 		result.has_parent = flags & METADATA_HAS_PARENT_FLAG != 0;
 		
-		return result;
+		return Ok(result);
 	}
 }
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct ParentLocatorEntry
 {
+	#[serde(serialize_with = "json::dec_hex")]
 	pub key_offset: u32,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub value_offset: u32,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub key_length: u16,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub value_length: u16,
 	pub key: String,
 	pub value: String,
@@ -160,31 +260,27 @@ pub struct ParentLocatorEntry
 
 impl ParentLocatorEntry
 {
-	pub fn new(data: &mut (impl Read + Seek), table_offset: usize) -> Self
+	pub fn new(data: &mut (impl Read + Seek), offset: u64, table_offset: u64) -> Fallible<Self>
 	{
 		let mut result = ParentLocatorEntry::default();
 		
-		result.key_offset.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read parent locator entry key offset u32: {:?}", error)});
-		result.value_offset.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read parent locator entry value offset u32: {:?}", error)});
-		result.key_length.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read parent locator entry key length u16: {:?}", error)});
-		result.value_length.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read parent locator entry value length u16: {:?}", error)});
+		result.key_offset.read_value(data).map_err(|error| VhdxError::read("parent locator entry key offset u32", offset, error))?;
+		result.value_offset.read_value(data).map_err(|error| VhdxError::read("parent locator entry value offset u32", offset + 0x4, error))?;
+		result.key_length.read_value(data).map_err(|error| VhdxError::read("parent locator entry key length u16", offset + 0x8, error))?;
+		result.value_length.read_value(data).map_err(|error| VhdxError::read("parent locator entry value length u16", offset + 0xA, error))?;
 			
 		result.key = String::with_capacity((result.key_length / 2) as usize);
-		result.key.read_value_off(data, table_offset + result.key_offset as usize).unwrap_or_else(|error| {
-			panic!("Failed to read parent locator entry key String: {:?}", error)});
+		result.key.read_value_off(data, table_offset + result.key_offset as u64).map_err(|error| {
+			VhdxError::read("parent locator entry key String", table_offset + result.key_offset as u64, error)})?;
 		result.value = String::with_capacity((result.value_length / 2) as usize);
-		result.value.read_value_off(data, table_offset + result.value_offset as usize).unwrap_or_else(|error| {
-			panic!("Failed to read parent locator entry value String: {:?}", error)});
+		result.value.read_value_off(data, table_offset + result.value_offset as u64).map_err(|error| {
+			VhdxError::read("parent locator entry value String", table_offset + result.value_offset as u64, error)})?;
 		
-		return result;
+		return Ok(result);
 	}
 }
 
-#[derive(PartialEq, Default, Clone)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub enum ParentLocatorType
 {
 	#[default]
@@ -192,7 +288,16 @@ pub enum ParentLocatorType
 	Vhdx,
 }
 
-#[derive(PartialEq, Default)]
+impl fmt::Display for ParentLocatorType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ParentLocatorType::Vhdx => write!(f, "VHDX"),
+			ParentLocatorType::Unknown => write!(f, "Unknown"),
+		}
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct ParentLocatorDict
 {
 	pub locator_type: ParentLocatorType,
@@ -203,29 +308,54 @@ pub struct ParentLocatorDict
 
 impl ParentLocatorDict
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), offset: u64) -> Fallible<Self>
 	{
 		let mut result = ParentLocatorDict::default();
 		
-		result.locator_type_id.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read parent locator type Uuid: {:?}", error)});
+		result.locator_type_id.read_value(data).map_err(|error| VhdxError::read("parent locator type Uuid", offset, error))?;
 		//Skip an internal reserved value...
-		data.seek(SeekFrom::Current(METADATA_PARENT_LOCATOR_HEADER_RESERVED_1_LEN as i64)).unwrap_or_else(|error| {
-			panic!("Failed to skip reserved region of size 0x{:X}: {:?}", METADATA_PARENT_LOCATOR_HEADER_RESERVED_1_LEN, error)});
-		result.key_value_count.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read parent locator key/value count u16: {:?}", error)});
+		data.seek(SeekFrom::Current(METADATA_PARENT_LOCATOR_HEADER_RESERVED_1_LEN as i64))?;
+		result.key_value_count.read_value(data).map_err(|error| VhdxError::read("parent locator key/value count u16", offset + 0x12, error))?;
+		ensure!(result.key_value_count <= MAX_PARENT_LOCATOR_ENTRIES, InvalidMetadata,
+			"Parent locator key/value count {} exceeds the maximum {}.", result.key_value_count, MAX_PARENT_LOCATOR_ENTRIES);
 		result.entries.reserve(result.key_value_count as usize);
 		
-		return result;
+		return Ok(result);
 	}
 
 	pub fn add_entry(self: &mut Self, entry: ParentLocatorEntry) -> ()
 	{
 		self.entries.push(entry);
 	}
+
+	pub fn unknown_keys(self: &Self) -> Vec<&str>
+	{
+		return self.entries.iter().map(|x| x.key.as_str()).filter(|x| !PARENT_LOCATOR_KNOWN_KEYS.contains(x)).collect();
+	}
 }
 
-#[derive(PartialEq, Default, Clone)]
+impl fmt::Display for ParentLocatorDict {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "	Parent locator contains:")?;
+		writeln!(f, "		Locator type:			{}.", self.locator_type)?;
+		writeln!(f, "		Locator type ID:		{}.", self.locator_type_id)?;
+		writeln!(f, "		Locator key/value count:	0x{:X}.", self.key_value_count)?;
+		for entry in &self.entries
+		{
+			writeln!(f, "			Key offset:		{}.", format_offset(entry.key_offset as u64, f.alternate()))?;
+			writeln!(f, "			Key length:		{}.", format_offset(entry.key_length as u64, f.alternate()))?;
+			writeln!(f, "			Key:			{}.", entry.key)?;
+			writeln!(f)?;
+			writeln!(f, "			Value offset:		{}.", format_offset(entry.value_offset as u64, f.alternate()))?;
+			writeln!(f, "			Value length:		{}.", format_offset(entry.value_length as u64, f.alternate()))?;
+			writeln!(f, "			Value:			{}.", entry.value)?;
+			writeln!(f)?;
+		}
+		return Ok(());
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct ParentLocator
 {
 	pub locator_type: ParentLocatorType,
@@ -236,51 +366,104 @@ pub struct ParentLocator
 	pub absolute_win32_path: String,
 }
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Clone)]
+pub enum ParentLinkageMatch
+{
+	Linkage1,
+	Linkage2,
+	None,
+}
+
+impl ParentLocator
+{
+	pub fn matches_parent(self: &Self, parent_data_write_id: Uuid) -> ParentLinkageMatch
+	{
+		if self.parent_linkage == parent_data_write_id
+		{
+			return ParentLinkageMatch::Linkage1;
+		}
+		else if self.parent_linkage2 == parent_data_write_id
+		{
+			return ParentLinkageMatch::Linkage2;
+		}
+		return ParentLinkageMatch::None;
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct Metadata
 {
 	pub file_parameters: FileParameters,
-	pub virtual_disk_size: usize,
+	#[serde(serialize_with = "json::dec_hex")]
+	pub virtual_disk_size: u64,
 	pub virtual_disk_id: Uuid,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub logical_sector_size: u32,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub physical_sector_size: u32,
+	pub physical_sector_size_assumed: bool,
 	pub parent_locator_dict: Option<ParentLocatorDict>,
 	pub parent_locator: Option<ParentLocator>,
 }
 
-fn read_file_parameters(data: &mut File, item_data: &MetadataTableEntry, table_offset: usize) -> Fallible<FileParameters>
+impl fmt::Display for Metadata {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "	Block size is:				{}.", format_size(self.file_parameters.block_size as u64))?;
+		writeln!(f, "	Leave block allocated:			{}.", self.file_parameters.leave_block_allocated)?;
+		writeln!(f, "	Has parent:				{}.", self.file_parameters.has_parent)?;
+		writeln!(f, "	Virtual disk size:			{}.", format_size(self.virtual_disk_size))?;
+		writeln!(f, "	Virtual disk ID:			{}.", self.virtual_disk_id)?;
+		writeln!(f, "	Logical sector size:			0x{:X}.", self.logical_sector_size)?;
+		if self.physical_sector_size_assumed
+		{
+			writeln!(f, "	Physical sector size:			0x{:X} (recorded as 0, defaulted).", self.physical_sector_size)?;
+		}
+		else
+		{
+			writeln!(f, "	Physical sector size:			0x{:X}.", self.physical_sector_size)?;
+		}
+		match &self.parent_locator_dict
+		{
+			Some(locator) if f.alternate() => write!(f, "{:#}", locator),
+			Some(locator) => write!(f, "{}", locator),
+			None => write!(f, "\n	Parent locator absent, disk is the head of its chain.\n\n"),
+		}
+	}
+}
+
+fn read_file_parameters(data: &mut (impl Read + Seek), item_data: &MetadataTableEntry, table_offset: u64) -> Fallible<FileParameters>
 {
-	data.seek(SeekFrom::Start((table_offset + item_data.object_offset as usize) as u64))?;
-	return Ok(FileParameters::new(data));
+	data.seek(SeekFrom::Start(table_offset + item_data.object_offset as u64))?;
+	return FileParameters::new(data, table_offset + item_data.object_offset as u64);
 }
 
-fn read_virtual_disk_size(data: &mut File, item_data: &MetadataTableEntry, table_offset: usize) -> Fallible<usize>
+fn read_virtual_disk_size(data: &mut (impl Read + Seek), item_data: &MetadataTableEntry, table_offset: u64) -> Fallible<u64>
 {
-	data.seek(SeekFrom::Start((table_offset + item_data.object_offset as usize) as u64))?;
-	let mut result: usize = 0;
+	data.seek(SeekFrom::Start(table_offset + item_data.object_offset as u64))?;
+	let mut result: u64 = 0;
 	result.read_value(data)?;
 	return Ok(result);
 }
 
-fn read_virtual_disk_id(data: &mut File, item_data: &MetadataTableEntry, table_offset: usize) -> Fallible<Uuid>
+fn read_virtual_disk_id(data: &mut (impl Read + Seek), item_data: &MetadataTableEntry, table_offset: u64) -> Fallible<Uuid>
 {
-	data.seek(SeekFrom::Start((table_offset + item_data.object_offset as usize) as u64))?;
+	data.seek(SeekFrom::Start(table_offset + item_data.object_offset as u64))?;
 	let mut result: Uuid = Uuid::default();
 	result.read_value(data)?;
 	return Ok(result);
 }
 
-fn read_logical_sector_size(data: &mut File, item_data: &MetadataTableEntry, table_offset: usize) -> Fallible<u32>
+fn read_logical_sector_size(data: &mut (impl Read + Seek), item_data: &MetadataTableEntry, table_offset: u64) -> Fallible<u32>
 {
-	data.seek(SeekFrom::Start((table_offset + item_data.object_offset as usize) as u64))?;
+	data.seek(SeekFrom::Start(table_offset + item_data.object_offset as u64))?;
 	let mut result: u32 = 0;
 	result.read_value(data)?;
 	return Ok(result);
 }
 
-fn read_physical_sector_size(data: &mut File, item_data: &MetadataTableEntry, table_offset: usize) -> Fallible<u32>
+fn read_physical_sector_size(data: &mut (impl Read + Seek), item_data: &MetadataTableEntry, table_offset: u64) -> Fallible<u32>
 {
-	data.seek(SeekFrom::Start((table_offset + item_data.object_offset as usize) as u64))?;
+	data.seek(SeekFrom::Start(table_offset + item_data.object_offset as u64))?;
 	let mut result: u32 = 0;
 	result.read_value(data)?;
 	return Ok(result);
@@ -288,27 +471,27 @@ fn read_physical_sector_size(data: &mut File, item_data: &MetadataTableEntry, ta
 
 fn check_parent_locator_entry_valid(entry: &ParentLocatorEntry) -> Fallible<()>
 {
-	ensure!(!entry.key.contains('\0'), "Parent locator value {} contains a null.");
-	ensure!(!entry.value.contains('\0'), "Parent locator value {} contains a null.");
+	ensure!(!entry.key.contains('\0'), InvalidMetadata, "Parent locator key {} contains a null.", entry.key);
+	ensure!(!entry.value.contains('\0'), InvalidMetadata, "Parent locator value {} contains a null.", entry.value);
 	
 	return Ok(());
 }
 
-fn read_parent_locator_entry(data: &mut File, item_offset: usize, table_offset: usize) -> Fallible<ParentLocatorEntry>
+fn read_parent_locator_entry(data: &mut (impl Read + Seek), item_offset: u64, table_offset: u64) -> Fallible<ParentLocatorEntry>
 {
-	data.seek(SeekFrom::Start(item_offset as u64))?;
-	let entry = ParentLocatorEntry::new(data, table_offset);
+	data.seek(SeekFrom::Start(item_offset))?;
+	let entry = ParentLocatorEntry::new(data, item_offset, table_offset)?;
 
 	check_parent_locator_entry_valid(&entry)?;
 
 	return Ok(entry);
 }
 
-fn read_parent_locator(data: &mut File, item_data: &MetadataTableEntry, table_offset: usize) -> Fallible<(Option<ParentLocatorDict>, Option<ParentLocator>)>
+fn read_parent_locator(data: &mut (impl Read + Seek), item_data: &MetadataTableEntry, table_offset: u64) -> Fallible<(Option<ParentLocatorDict>, Option<ParentLocator>)>
 {
-	data.seek(SeekFrom::Start((table_offset + item_data.object_offset as usize) as u64))?;
+	data.seek(SeekFrom::Start(table_offset + item_data.object_offset as u64))?;
 
-	let mut table = ParentLocatorDict::new(data);
+	let mut table = ParentLocatorDict::new(data, table_offset + item_data.object_offset as u64)?;
 	let mut locator = ParentLocator::default();
 	table.locator_type = match table.locator_type_id
 	{
@@ -318,8 +501,8 @@ fn read_parent_locator(data: &mut File, item_data: &MetadataTableEntry, table_of
 
 	for n in 0..table.key_value_count as usize
 	{
-		let item_offset = table_offset + item_data.object_offset as usize + METADATA_PARENT_LOCATOR_HEADER_LEN + (n * METADATA_PARENT_LOCATOR_ENTRY_LEN);
-		let entry = read_parent_locator_entry(data, item_offset, table_offset + item_data.object_offset as usize)?;
+		let item_offset = table_offset + item_data.object_offset as u64 + (METADATA_PARENT_LOCATOR_HEADER_LEN + (n * METADATA_PARENT_LOCATOR_ENTRY_LEN)) as u64;
+		let entry = read_parent_locator_entry(data, item_offset, table_offset + item_data.object_offset as u64)?;
 
 		match entry.key.as_str()
 		{
@@ -328,27 +511,51 @@ fn read_parent_locator(data: &mut File, item_data: &MetadataTableEntry, table_of
 			PARENT_LOCATOR_RELATIVE_PATH_KEY => locator.relative_path = entry.value.clone(),
 			PARENT_LOCATOR_VOLUME_PATH_KEY => locator.volume_path = entry.value.clone(),
 			PARENT_LOCATOR_ABSOLUTE_PATH_KEY => locator.absolute_win32_path = entry.value.clone(),
-			&_ => ensure!(false, "Unknown parent locator key '{}'.", entry.key),
+			// Keys added by other tools are kept in the table and reported as a finding.
+			&_ => {},
 		}
 
 		table.add_entry(entry);
 	}
+	ensure!(table.locator_type != ParentLocatorType::Vhdx || table.entries.iter().any(|x| x.key == PARENT_LOCATOR_LINKAGE1_KEY),
+		InvalidMetadata, "Parent locator does not contain the required key '{}'.", PARENT_LOCATOR_LINKAGE1_KEY);
 	locator.locator_type = table.locator_type.clone();
 
 	return Ok((Some(table), Some(locator)));
 }
 
-fn check_metadata_table_entry_valid(entry: &MetadataTableEntry) -> Fallible<()>
+fn check_metadata_item_in_region(entry: &MetadataTableEntry, table_length: u64) -> Fallible<()>
+{
+	ensure!(entry.object_offset as u64 + entry.object_length as u64 <= table_length,
+		InvalidMetadata, "Metadata item {} at offset 0x{:X} with length 0x{:X} extends past the end of the metadata region ({} bytes).",
+		entry.object_id, entry.object_offset, entry.object_length, table_length);
+
+	return Ok(());
+}
+
+pub fn check_metadata_items_in_region(table: &MetadataTable, region_data: &RegionTableEntry) -> Fallible<()>
+{
+	for entry in &table.entries
+	{
+		check_metadata_item_in_region(entry, region_data.object_length as u64)?;
+	}
+
+	return Ok(());
+}
+
+fn check_metadata_table_entry_valid(entry: &MetadataTableEntry, table_length: usize) -> Fallible<()>
 {
-	ensure!(entry.metadata_type != MetadataType::Unknown || !entry.is_required, "Metadata header signature is invalid.");
+	ensure!(entry.metadata_type != MetadataType::Unknown || !entry.is_required, InvalidMetadata,
+		"Metadata item {} is marked as required but is not recognised.", entry.object_id);
+	check_metadata_item_in_region(entry, table_length as u64)?;
 	
 	return Ok(());
 }
 
-fn read_metadata_entry(data: &mut File, table_offset: usize) -> Fallible<MetadataTableEntry>
+fn read_metadata_entry(data: &mut (impl Read + Seek), entry_offset: u64, table_length: usize) -> Fallible<MetadataTableEntry>
 {
-	data.seek(SeekFrom::Start(table_offset as u64))?;
-	let mut entry = MetadataTableEntry::new(data);
+	data.seek(SeekFrom::Start(entry_offset))?;
+	let mut entry = MetadataTableEntry::new(data, entry_offset)?;
 	entry.metadata_type = match entry.object_id
 	{
 		METADATA_FILE_PARAMETERS => MetadataType::FileParameters,
@@ -360,21 +567,21 @@ fn read_metadata_entry(data: &mut File, table_offset: usize) -> Fallible<Metadat
 		_ => MetadataType::Unknown,
 	};
 
-	check_metadata_table_entry_valid(&entry)?;
+	check_metadata_table_entry_valid(&entry, table_length)?;
 
 	return Ok(entry);
 }
 
 fn check_metadata_table_header_valid(signature: &[u8]) -> Fallible<()>
 {
-	ensure!(signature == METADATA_HEADER_SIG, "Metadata header signature is invalid.");
+	ensure!(signature == METADATA_HEADER_SIG, BadSignature, "Metadata header signature is invalid.");
 	
 	return Ok(());
 }
 
-fn read_metadata_table(data: &mut File, table_offset: usize, table_length: usize) -> Fallible<MetadataTable>
+fn read_metadata_table(data: &mut (impl Read + Seek), table_offset: u64, table_length: usize) -> Fallible<MetadataTable>
 {
-	data.seek(SeekFrom::Start(table_offset as u64))?;
+	data.seek(SeekFrom::Start(table_offset))?;
 
 	let mut signature:Vec<u8> = vec![0;METADATA_HEADER_SIG_LEN];
 	signature.read_value(data)?;
@@ -382,22 +589,33 @@ fn read_metadata_table(data: &mut File, table_offset: usize, table_length: usize
 	//Skip an internal reserved value...
 	data.seek(SeekFrom::Current(METADATA_HEADER_RESERVED_1_LEN as i64))?;
 
-	let mut table = MetadataTable::new(data);
+	let mut table = MetadataTable::new(data, table_offset)?;
 	
 	check_metadata_table_header_valid(&signature)?;
 
 	for n in 0..table.entry_count as usize
 	{
-		ensure!(n * METADATA_ENTRY_LEN <= table_length, "Metadata table is longer than recorded in the region table ({} bytes).", table_length);
-		table.add_entry(read_metadata_entry(data, table_offset + METADATA_HEADER_LEN + (n * METADATA_ENTRY_LEN))?);
+		ensure!(n * METADATA_ENTRY_LEN <= table_length, InvalidMetadata, "Metadata table is longer than recorded in the region table ({} bytes).", table_length);
+		table.add_entry(read_metadata_entry(data, table_offset + (METADATA_HEADER_LEN + (n * METADATA_ENTRY_LEN)) as u64, table_length)?);
 	}
 
 	return Ok(table);
 }
 
-fn read_metadata_values(data: &mut File, table: &MetadataTable, table_offset: usize, table_length: usize) -> Fallible<Metadata>
+fn check_required_metadata_present(table: &MetadataTable) -> Fallible<()>
 {
-	data.seek(SeekFrom::Start(table_offset as u64))?;
+	let missing: Vec<String> = REQUIRED_METADATA_TYPES.iter()
+		.filter(|x| !table.entries.iter().any(|entry| entry.metadata_type == **x))
+		.map(|x| x.to_string())
+		.collect();
+	ensure!(missing.is_empty(), InvalidMetadata, "Metadata table is missing required items: {}.", missing.join(", "));
+
+	return Ok(());
+}
+
+fn read_metadata_values(data: &mut (impl Read + Seek), table: &MetadataTable, table_offset: u64) -> Fallible<Metadata>
+{
+	data.seek(SeekFrom::Start(table_offset))?;
 	let mut metadata = Metadata::default();
 
 	for item_data in &table.entries
@@ -410,32 +628,198 @@ fn read_metadata_values(data: &mut File, table: &MetadataTable, table_offset: us
 			MetadataType::LogicalSectorSize => { metadata.logical_sector_size = read_logical_sector_size(data, item_data, table_offset)? }
 			MetadataType::PhysicalSectorSize => { metadata.physical_sector_size = read_physical_sector_size(data, item_data, table_offset)? }
 			MetadataType::ParentLocator => { (metadata.parent_locator_dict,metadata.parent_locator) = read_parent_locator(data, item_data, table_offset)? }
-			MetadataType::Unknown => { ensure!(false, "Unknown metadata type {} encountered.", item_data.object_id); }
+			// Unknown items that are not required have already been let through and are left for the caller to report.
+			MetadataType::Unknown => {},
 		}
 	}
 
-	ensure!(data.stream_position()? <= (table_offset + table_length) as u64,
-		"Metadata table is longer than recorded in the region table ({} bytes).", table_length);
-
 	return Ok(metadata);
 }
 
+fn apply_metadata_defaults(metadata: &mut Metadata) -> ()
+{
+	// Some legacy writers record a physical sector size of zero to mean the 512 byte default, the logical size stays strict.
+	// A physical sector is never smaller than a logical one, so a 4096 byte logical sector takes its own size instead.
+	if metadata.physical_sector_size == 0
+	{
+		metadata.physical_sector_size = DEFAULT_PHYSICAL_SECTOR_SIZE.max(metadata.logical_sector_size);
+		metadata.physical_sector_size_assumed = true;
+	}
+}
+
+fn check_sector_sizes(metadata: &Metadata) -> Fallible<()>
+{
+	ensure!(VALID_SECTOR_SIZES.contains(&metadata.logical_sector_size),
+		InvalidMetadata, "Logical sector size {} is invalid, must be 512 or 4096.", metadata.logical_sector_size);
+	ensure!(VALID_SECTOR_SIZES.contains(&metadata.physical_sector_size),
+		InvalidMetadata, "Physical sector size {} is invalid, must be 512 or 4096.", metadata.physical_sector_size);
+
+	return Ok(());
+}
+
+fn check_block_size(metadata: &Metadata) -> Fallible<()>
+{
+	let block_size = metadata.file_parameters.block_size;
+	ensure!(block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size),
+		InvalidMetadata, "Block size 0x{:X} is invalid, must be a power of two between 0x{:X} and 0x{:X}.", block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+
+	return Ok(());
+}
+
+fn check_virtual_disk_size(metadata: &Metadata) -> Fallible<()>
+{
+	ensure!(metadata.virtual_disk_size != 0, InvalidMetadata, "Virtual disk size is 0, a disk must hold at least one sector.");
+	ensure!(metadata.virtual_disk_size.is_multiple_of(metadata.logical_sector_size as u64),
+		InvalidMetadata, "Virtual disk size 0x{:X} is invalid, must be a multiple of the logical sector size 0x{:X}.",
+		metadata.virtual_disk_size, metadata.logical_sector_size);
+
+	return Ok(());
+}
+
 fn check_metadata_valid(metadata: &Metadata) -> Fallible<()>
 {
+	// The sector sizes are checked first, the virtual disk size check relies on a valid logical sector size.
+	check_sector_sizes(metadata)?;
+	check_virtual_disk_size(metadata)?;
+	check_block_size(metadata)?;
 	ensure!(!metadata.file_parameters.has_parent || metadata.parent_locator.is_some(),
-		"File parameter 'HasParent' is set and the file does not contain a parent locator.");
+		InvalidMetadata, "File parameter 'HasParent' is set and the file does not contain a parent locator.");
 	
 	return Ok(());
 }
 
-pub fn read_metadata(data: &mut File, region_data: &RegionTableEntry) -> Fallible<(MetadataTable, Metadata)>
+pub fn get_raw_metadata(data: &mut (impl Read + Seek), region_data: &RegionTableEntry, table: &MetadataTable, object_id: Uuid) -> Fallible<Vec<u8>>
 {
 	ensure!(region_data.region_type == RegionType::Metadata, "Passed region data is not for the Metadata region.");
+	let entry = table.entries.iter().find(|x| x.object_id == object_id).ok_or_else(|| {
+		format_err!("Metadata item {} is not in the metadata table.", object_id)})?;
+	check_metadata_item_in_region(entry, region_data.object_length as u64)?;
 
-	let table = read_metadata_table(data, region_data.object_offset as usize, region_data.object_length as usize)?;
-	let metadata = read_metadata_values(data, &table, region_data.object_offset as usize, region_data.object_length as usize)?;
+	let mut buffer: Vec<u8> = vec![0;entry.object_length as usize];
+	read_into(data, region_data.object_offset + entry.object_offset as u64, &mut buffer)?;
 
+	return Ok(buffer);
+}
+
+pub fn read_metadata(data: &mut (impl Read + Seek), region_data: &RegionTableEntry) -> Fallible<(MetadataTable, Metadata)>
+{
+	ensure!(region_data.region_type == RegionType::Metadata, "Passed region data is not for the Metadata region.");
+	ensure!(region_data.object_length >= METADATA_REGION_ALIGNMENT && region_data.object_length.is_multiple_of(METADATA_REGION_ALIGNMENT),
+		InvalidMetadata, "Metadata region length 0x{:X} is invalid, must be a non-zero multiple of 0x{:X}.", region_data.object_length, METADATA_REGION_ALIGNMENT);
+
+	let table = read_metadata_table(data, region_data.object_offset, region_data.object_length as usize)?;
+	check_required_metadata_present(&table)?;
+	let mut metadata = read_metadata_values(data, &table, region_data.object_offset)?;
+
+	apply_metadata_defaults(&mut metadata);
 	check_metadata_valid(&metadata)?;
 
 	return Ok((table, metadata));
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+	use std::io::Cursor;
+
+	use super::*;
+
+	fn metadata_with_sectors(logical_sector_size: u32, physical_sector_size: u32) -> Metadata
+	{
+		let mut metadata = Metadata::default();
+		metadata.file_parameters.block_size = MIN_BLOCK_SIZE;
+		metadata.virtual_disk_size = MIN_BLOCK_SIZE as u64;
+		metadata.logical_sector_size = logical_sector_size;
+		metadata.physical_sector_size = physical_sector_size;
+		return metadata;
+	}
+
+	#[test]
+	fn parent_linkage_matches()
+	{
+		let mut locator = ParentLocator::default();
+		locator.parent_linkage = uuid!("6A1B9E51-0C35-4E0B-9C0A-3E7C2C1F0001");
+		locator.parent_linkage2 = uuid!("6A1B9E51-0C35-4E0B-9C0A-3E7C2C1F0002");
+
+		assert_eq!(locator.matches_parent(locator.parent_linkage), ParentLinkageMatch::Linkage1);
+		assert_eq!(locator.matches_parent(locator.parent_linkage2), ParentLinkageMatch::Linkage2);
+		assert_eq!(locator.matches_parent(uuid!("6A1B9E51-0C35-4E0B-9C0A-3E7C2C1F0003")), ParentLinkageMatch::None);
+	}
+
+	#[test]
+	fn zero_physical_sector_size_is_defaulted()
+	{
+		for logical_sector_size in VALID_SECTOR_SIZES
+		{
+			let mut metadata = metadata_with_sectors(logical_sector_size, 0);
+			apply_metadata_defaults(&mut metadata);
+
+			assert_eq!(metadata.physical_sector_size, logical_sector_size);
+			assert!(metadata.physical_sector_size >= metadata.logical_sector_size);
+			assert!(metadata.physical_sector_size_assumed);
+			assert_eq!(metadata.logical_sector_size, logical_sector_size);
+			check_metadata_valid(&metadata).unwrap();
+		}
+	}
+
+	#[test]
+	fn misaligned_virtual_disk_size_is_rejected()
+	{
+		let mut metadata = metadata_with_sectors(4096, 4096);
+		metadata.virtual_disk_size = MIN_BLOCK_SIZE as u64 + 512;
+
+		let error = check_metadata_valid(&metadata).unwrap_err();
+		assert_eq!(error.to_string(), "Virtual disk size 0x100200 is invalid, must be a multiple of the logical sector size 0x1000.");
+		assert!(matches!(error, VhdxError::InvalidMetadata(_)));
+
+		metadata.logical_sector_size = 512;
+		check_metadata_valid(&metadata).unwrap();
+	}
+
+	#[test]
+	fn sector_size_is_checked_before_virtual_disk_size()
+	{
+		let mut metadata = metadata_with_sectors(0, 4096);
+		metadata.virtual_disk_size = MIN_BLOCK_SIZE as u64 + 1;
+
+		let error = check_metadata_valid(&metadata).unwrap_err();
+		assert!(error.to_string().starts_with("Logical sector size 0 is invalid"));
+	}
+
+	#[test]
+	fn zero_virtual_disk_size_is_rejected()
+	{
+		let mut metadata = metadata_with_sectors(512, 512);
+		metadata.virtual_disk_size = 0;
+
+		assert!(matches!(check_metadata_valid(&metadata), Err(VhdxError::InvalidMetadata(_))));
+	}
+
+	#[test]
+	fn recorded_physical_sector_size_is_kept()
+	{
+		let mut metadata = metadata_with_sectors(512, 4096);
+		apply_metadata_defaults(&mut metadata);
+
+		assert_eq!(metadata.physical_sector_size, 4096);
+		assert!(!metadata.physical_sector_size_assumed);
+	}
+
+	#[test]
+	fn metadata_table_round_trip()
+	{
+		let mut table = MetadataTable::default();
+		table.add_entry(MetadataTableEntry{metadata_type: MetadataType::FileParameters, object_id: METADATA_FILE_PARAMETERS,
+			object_offset: 0x10000, object_length: 0x8, is_required: true, ..Default::default()});
+		table.add_entry(MetadataTableEntry{metadata_type: MetadataType::VirtualDiskSize, object_id: METADATA_VIRTUAL_DISK_SIZE,
+			object_offset: 0x10008, object_length: 0x8, is_virtual_disk: true, is_required: true, ..Default::default()});
+		table.add_entry(MetadataTableEntry{metadata_type: MetadataType::Unknown, object_id: Uuid::from_u128(1),
+			object_offset: 0x10010, object_length: 0x10, is_user: true, ..Default::default()});
+		table.entry_count = table.entries.len() as u16;
+
+		let mut data = Cursor::new(Vec::<u8>::new());
+		table.write_to(&mut data).unwrap();
+		assert_eq!(data.get_ref().len(), METADATA_TABLE_LEN);
+
+		assert_eq!(read_metadata_table(&mut data, 0, METADATA_REGION_ALIGNMENT as usize * 2).unwrap(), table);
+	}
+}
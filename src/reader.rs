@@ -1,25 +1,45 @@
 // SPDX-License-Identifier: MIT
 // Copyright (c) Nick Moss.
 
-use std::io::{Seek, Read, SeekFrom};
+use std::io::{self, Seek, Read, SeekFrom};
 
 use byteorder::{LittleEndian,ReadBytesExt};
-use failure::Fallible;
 use num::PrimInt;
 use uuid::Uuid;
 
-pub fn read_into(data: &mut (impl Read + Seek), offset: usize, buffer: &mut Vec<u8>) -> Fallible<()>
+use crate::error::{Fallible,VhdxError};
+
+pub fn read_into(data: &mut (impl Read + Seek), offset: u64, buffer: &mut [u8]) -> Fallible<()>
 {
-	data.seek(SeekFrom::Start(offset as u64))?;
-	let _ = data.read_exact(buffer)?;
+	// A short file is still reported as an I/O error, but with the shortfall spelled out rather than a bare end of file.
+	let available = data.seek(SeekFrom::End(0))?.saturating_sub(offset);
+	if (buffer.len() as u64) > available
+	{
+		return Err(VhdxError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+			format!("Requested {} bytes at offset 0x{:X} but only {} are available.", buffer.len(), offset, available))));
+	}
+
+	data.seek(SeekFrom::Start(offset))?;
+	data.read_exact(buffer)?;
 
 	return Ok(());
 }
 
+pub fn read_ascii(data: &mut impl Read, length: usize) -> Fallible<String>
+{
+	let mut buffer: Vec<u8> = vec![0;length];
+	data.read_exact(&mut buffer)?;
+	// As with the UTF-16 fields, only the text up to the first NUL is meaningful.
+	let length = buffer.iter().position(|x| *x == 0).unwrap_or(buffer.len());
+	buffer.truncate(length);
+
+	return Ok(String::from_utf8(buffer)?);
+}
+
 pub trait ReadValueOtherTyped
 {
 	fn read_value<T>(&mut self, data: &mut (impl Read + Seek)) -> Fallible<()> where Self: Sized, T: Default + ReadValue + PrimInt;
-	fn read_value_off<T>(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()> where Self: Sized, T: Default + ReadValue + PrimInt;
+	fn read_value_off<T>(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()> where Self: Sized, T: Default + ReadValue + PrimInt;
 }
 
 impl ReadValueOtherTyped for bool
@@ -32,9 +52,9 @@ impl ReadValueOtherTyped for bool
 		return Ok(());
 	}
 
-	fn read_value_off<T: Default + ReadValue + PrimInt>(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off<T: Default + ReadValue + PrimInt>(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value::<T>(data)?;
 		return Ok(());
 	}
@@ -43,7 +63,7 @@ impl ReadValueOtherTyped for bool
 pub trait ReadValue
 {
 	fn read_value(&mut self, data: &mut (impl Read + Seek)) -> Fallible<()> where Self: Sized;
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()> where Self: Sized;
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()> where Self: Sized;
 }
 
 impl ReadValue for u16
@@ -54,9 +74,9 @@ impl ReadValue for u16
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
@@ -70,9 +90,9 @@ impl ReadValue for u32
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
@@ -86,9 +106,9 @@ impl ReadValue for u64
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
@@ -102,25 +122,41 @@ impl ReadValue for u128
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
+	{
+		data.seek(SeekFrom::Start(offset))?;
+		self.read_value(data)?;
+		return Ok(());
+	}
+}
+
+impl ReadValue for i32
+{
+	fn read_value(&mut self, data: &mut (impl Read + Seek)) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		*self = data.read_i32::<LittleEndian>()?;
+		return Ok(());
+	}
+
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
+	{
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
 }
 
-impl ReadValue for usize
+impl ReadValue for i64
 {
 	fn read_value(&mut self, data: &mut (impl Read + Seek)) -> Fallible<()>
 	{
-		*self = data.read_u64::<LittleEndian>()? as usize;
+		*self = data.read_i64::<LittleEndian>()?;
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
@@ -136,9 +172,9 @@ impl ReadValue for Uuid
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
@@ -152,9 +188,9 @@ impl ReadValue for Vec<u8>
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
@@ -168,9 +204,9 @@ impl ReadValue for Vec<u16>
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
@@ -182,14 +218,82 @@ impl ReadValue for String
 	{
 		let mut creator_u16:Vec<u16> = vec![0;self.capacity()];
 		creator_u16.read_value(data)?;
-		*self = String::from_utf16(&creator_u16)?;
+		// Fields are NUL padded out to their full size, only the text up to the first NUL is meaningful.
+		let length = creator_u16.iter().position(|x| *x == 0).unwrap_or(creator_u16.len());
+		*self = String::from_utf16(&creator_u16[..length])?;
 		return Ok(());
 	}
 
-	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: usize) -> Fallible<()>
+	fn read_value_off(&mut self, data: &mut (impl Read + Seek), offset: u64) -> Fallible<()>
 	{
-		data.seek(SeekFrom::Start(offset as u64))?;
+		data.seek(SeekFrom::Start(offset))?;
 		self.read_value(data)?;
 		return Ok(());
 	}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+	use std::io::Cursor;
+
+	use super::*;
+
+	#[test]
+	fn signed_values_read_little_endian()
+	{
+		let mut data = Cursor::new(vec![0xFE, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x78, 0x56, 0x34, 0x12]);
+
+		let mut small: i32 = 0;
+		small.read_value(&mut data).unwrap();
+		assert_eq!(small, -2);
+
+		let mut large: i64 = 0;
+		large.read_value(&mut data).unwrap();
+		assert_eq!(large, i64::MIN);
+
+		small.read_value_off(&mut data, 12).unwrap();
+		assert_eq!(small, 0x12345678);
+		large.read_value_off(&mut data, 0).unwrap();
+		assert_eq!(large, 0x00000000_FFFFFFFE);
+	}
+
+	#[test]
+	fn signed_values_round_trip()
+	{
+		for value in [0_i64, 1, -1, i64::MAX, i64::MIN, -0x1234_5678_9ABC]
+		{
+			let mut read: i64 = 0;
+			read.read_value(&mut Cursor::new(value.to_le_bytes())).unwrap();
+			assert_eq!(read, value);
+		}
+		for value in [0_i32, 1, -1, i32::MAX, i32::MIN]
+		{
+			let mut read: i32 = 0;
+			read.read_value(&mut Cursor::new(value.to_le_bytes())).unwrap();
+			assert_eq!(read, value);
+		}
+	}
+
+	#[test]
+	fn ascii_stops_at_first_nul()
+	{
+		let mut data = Cursor::new(b"conectix\0\0\0\0vpc \0\0\0\0".to_vec());
+
+		assert_eq!(read_ascii(&mut data, 12).unwrap(), "conectix");
+		assert_eq!(read_ascii(&mut data, 8).unwrap(), "vpc ");
+		assert!(read_ascii(&mut data, 1).is_err());
+	}
+
+	#[test]
+	fn invalid_ascii_is_an_error()
+	{
+		assert!(read_ascii(&mut Cursor::new(vec![0x76, 0xFF, 0x63]), 3).is_err());
+	}
+
+	#[test]
+	fn short_signed_value_is_an_error()
+	{
+		let mut value: i64 = 0;
+		assert!(value.read_value(&mut Cursor::new(vec![0_u8; 4])).is_err());
+	}
+}
@@ -2,34 +2,39 @@
 // Copyright (c) Nick Moss.
 
 use std::{
-	fs::File,
-	io::{Seek, Read, SeekFrom},
+	fmt,
+	io::{Seek, Read, SeekFrom, Write},
 };
 
-use failure::{ensure,Fallible};
+use byteorder::{ByteOrder,LittleEndian,WriteBytesExt};
+use serde::Serialize;
 use uuid::{Uuid,uuid,};
 
+use crate::error::{Fallible,VhdxError};
+use crate::{ensure,format_err};
 use crate::checksum::*;
+use crate::json;
+use crate::maths::format_offset;
 use crate::reader::{read_into,ReadValue,ReadValueOtherTyped};
 
 const REGION_TAB_LEN: usize = 0x10000;
-const FIRST_REGION_TAB_OFFSET: usize = 0x30000;
-const SECOND_REGION_TAB_OFFSET: usize = 0x40000;
+pub const FIRST_REGION_TAB_OFFSET: usize = 0x30000;
+pub const SECOND_REGION_TAB_OFFSET: usize = 0x40000;
 const REGION_TAB_HEADER_LEN: usize = 0x10;
-const REGION_TAB_HEADER_SIG: [u8; REGION_TAB_HEADER_SIG_LEN] = [0x72, 0x65, 0x67, 0x69];
+pub const REGION_TAB_HEADER_SIG: [u8; REGION_TAB_HEADER_SIG_LEN] = [0x72, 0x65, 0x67, 0x69];
 const REGION_TAB_HEADER_SIG_LEN: usize = 0x4;
 const REGION_TAB_HEADER_CHECKSUM_LEN: usize = CHECKSUM_LENGTH;
 
 const REGION_TAB_ENTRY_LEN: usize = 0x20;
 
-const MAX_REGION_ENTRIES: u32 = 2047;
+pub const MAX_REGION_ENTRIES: u32 = ((REGION_TAB_LEN - REGION_TAB_HEADER_LEN) / REGION_TAB_ENTRY_LEN) as u32;
 const MIN_REGION_OFFSET: u64 = u64::pow(1024, 2);
 const REGION_OFFSET_FACTOR: u64 = u64::pow(1024, 2);
 const REGION_SIZE_FACTOR: u32 = u32::pow(1024, 2);
 const REGION_BAT: Uuid = uuid!("2DC27766-F623-4200-9D64-115E9BFD4A08");
 const REGION_METADATA: Uuid = uuid!("8B7CA206-4790-4B9A-B8FE-575F050F886E");
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Copy, Serialize)]
 pub enum RegionType
 {
 	#[default]
@@ -38,38 +43,57 @@ pub enum RegionType
 	Metadata,
 }
 
-#[derive(PartialEq, Default)]
+impl fmt::Display for RegionType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			RegionType::Unknown => write!(f, "unknown"),
+			RegionType::BAT => write!(f, "BAT"),
+			RegionType::Metadata => write!(f, "metadata"),
+		}
+	}
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct RegionTableEntry
 {
 	pub region_type: RegionType,
 	pub object_id: Uuid,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub object_offset: u64,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub object_length: u32,
 	pub required: bool
 }
 
 impl RegionTableEntry
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), offset: u64) -> Fallible<Self>
 	{
 		let mut result = RegionTableEntry::default();
 		
-		result.object_id.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read Region entry object ID Uuid: {:?}", error)});
-		result.object_offset.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read Region entry object offset u64: {:?}", error)});
-		result.object_length.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read Region entry file object length u32: {:?}", error)});
-		result.required.read_value::<u32>(data).unwrap_or_else(|error| {
-			panic!("Failed to read Region entry data required bool: {:?}", error)});
+		result.object_id.read_value(data).map_err(|error| VhdxError::read("Region entry object ID Uuid", offset, error))?;
+		result.object_offset.read_value(data).map_err(|error| VhdxError::read("Region entry object offset u64", offset + 0x10, error))?;
+		result.object_length.read_value(data).map_err(|error| VhdxError::read("Region entry file object length u32", offset + 0x18, error))?;
+		result.required.read_value::<u32>(data).map_err(|error| VhdxError::read("Region entry data required bool", offset + 0x1C, error))?;
 		
-		return result;
+		return Ok(result);
+	}
+
+	pub fn write_to(self: &Self, out: &mut impl Write) -> Fallible<()>
+	{
+		out.write_all(&self.object_id.to_bytes_le())?;
+		out.write_u64::<LittleEndian>(self.object_offset)?;
+		out.write_u32::<LittleEndian>(self.object_length)?;
+		out.write_u32::<LittleEndian>(self.required as u32)?;
+
+		return Ok(());
 	}
 }
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct RegionTable
 {
+	#[serde(serialize_with = "json::hex")]
 	pub checksum: u32,
 	pub entry_count: u32,
 	pub entries: Vec<RegionTableEntry>
@@ -77,44 +101,130 @@ pub struct RegionTable
 
 impl RegionTable
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), offset: u64) -> Fallible<Self>
 	{
 		let mut result = RegionTable::default();
 		
-		result.checksum.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read Region Header checksum u32: {:?}", error)});
-		result.entry_count.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read Region Header sequence number u32: {:?}", error)});
+		result.checksum.read_value(data).map_err(|error| VhdxError::read("Region Header checksum u32", offset + 0x4, error))?;
+		result.entry_count.read_value(data).map_err(|error| VhdxError::read("Region Header entry count u32", offset + 0x8, error))?;
+		// The count comes straight from the file, so it is bounded before anything is allocated for it.
+		ensure!(result.entry_count <= MAX_REGION_ENTRIES,
+			"Region table entry count {} exceeds the specified maximum {}.", result.entry_count, MAX_REGION_ENTRIES);
 		result.entries.reserve(result.entry_count as usize);
 		
-		return result;
+		return Ok(result);
+	}
+
+	pub fn find(self: &Self, region_type: RegionType) -> Option<&RegionTableEntry>
+	{
+		return self.entries.iter().find(|x| x.region_type == region_type);
+	}
+
+	pub fn find_required(self: &Self, region_type: RegionType) -> Fallible<&RegionTableEntry>
+	{
+		return self.find(region_type).ok_or_else(|| format_err!("Required {} region is absent.", region_type));
+	}
+
+	pub fn find_by_id(self: &Self, object_id: &Uuid) -> Option<&RegionTableEntry>
+	{
+		return self.entries.iter().find(|x| x.object_id == *object_id);
 	}
 
 	pub fn add_entry(self: &mut Self, entry: RegionTableEntry) -> ()
 	{
 		self.entries.push(entry);
 	}
+
+	pub fn write_to(self: &Self, out: &mut (impl Write + Seek)) -> Fallible<()>
+	{
+		ensure!(self.entries.len() <= MAX_REGION_ENTRIES as usize,
+			"Region table entry count exceeds the specified maximum {}.", MAX_REGION_ENTRIES);
+
+		let mut buffer: Vec<u8> = Vec::with_capacity(REGION_TAB_LEN);
+		buffer.write_all(&REGION_TAB_HEADER_SIG)?;
+		buffer.write_u32::<LittleEndian>(0)?;
+		buffer.write_u32::<LittleEndian>(self.entries.len() as u32)?;
+		buffer.write_u32::<LittleEndian>(0)?;
+		for entry in &self.entries
+		{
+			entry.write_to(&mut buffer)?;
+		}
+		buffer.resize(REGION_TAB_LEN, 0);
+
+		// The checksum covers the whole 64 KiB table with the checksum field itself zeroed.
+		let checksum = calculate_checksum(buffer.clone(), REGION_TAB_HEADER_SIG_LEN);
+		LittleEndian::write_u32(&mut buffer[REGION_TAB_HEADER_SIG_LEN..], checksum);
+		out.write_all(&buffer)?;
+
+		return Ok(());
+	}
+}
+
+impl fmt::Display for RegionTable {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "	Checksum is				0x{:X}.", self.checksum)?;
+		writeln!(f, "	Entry count is				0x{:X}.", self.entry_count)?;
+		writeln!(f, "	Regions:")?;
+		for entry in &self.entries
+		{
+			match entry.region_type
+			{
+				RegionType::BAT => writeln!(f, "		Type:				Block Allocation Table")?,
+				RegionType::Metadata => writeln!(f, "		Type:				Metadata")?,
+				RegionType::Unknown => writeln!(f, "		Type:			Unknown")?,
+			}
+			writeln!(f, "		Region ID:			{}.", entry.object_id)?;
+			writeln!(f, "		Region offset:			{}.", format_offset(entry.object_offset, f.alternate()))?;
+			writeln!(f, "		Region length:			{}.", format_offset(entry.object_length as u64, f.alternate()))?;
+			writeln!(f, "		Required:			{}.", entry.required)?;
+			writeln!(f)?;
+		}
+		return Ok(());
+	}
 }
 
 fn check_region_entry_valid(entry: &RegionTableEntry) -> Fallible<()>
 {
 	ensure!(entry.object_offset >= MIN_REGION_OFFSET,
-		format!("Region object offset is smaller than the specified minimum {}.", MIN_REGION_OFFSET));
-	ensure!(entry.object_offset % REGION_OFFSET_FACTOR == 0,
-		format!("Region object offset is not a multiple of the specified {}.", REGION_OFFSET_FACTOR));
-	ensure!(entry.object_length % REGION_SIZE_FACTOR == 0,
-		format!("Region object size is not a multiple of the specified {}.", REGION_SIZE_FACTOR));
+		"Region object offset is smaller than the specified minimum {}.", MIN_REGION_OFFSET);
+	ensure!(entry.object_offset.is_multiple_of(REGION_OFFSET_FACTOR),
+		"Region object offset is not a multiple of the specified {}.", REGION_OFFSET_FACTOR);
+	ensure!(entry.object_length.is_multiple_of(REGION_SIZE_FACTOR),
+		"Region object size is not a multiple of the specified {}.", REGION_SIZE_FACTOR);
 
 	ensure!(entry.region_type != RegionType::Unknown || !entry.required,
-		format!("Required object ID {} is not recognised by this version of this program.", entry.object_id));
+		"Required object ID {} is not recognised by this version of this program.", entry.object_id);
 	
 	return Ok(());
 }
 
-fn read_region_entry(data: &mut File, entry_offset: usize) -> Fallible<RegionTableEntry>
+fn region_end(entry: &RegionTableEntry) -> Fallible<u64>
+{
+	return entry.object_offset.checked_add(entry.object_length as u64).ok_or_else(|| format_err!(
+		"The {} region at offset 0x{:X} with length 0x{:X} runs past the largest possible file offset.", entry.region_type, entry.object_offset, entry.object_length));
+}
+
+pub fn check_region_overlap(table: &RegionTable) -> Fallible<()>
+{
+	let mut entries: Vec<&RegionTableEntry> = table.entries.iter().collect();
+	entries.sort_by_key(|x| x.object_offset);
+
+	for pair in entries.windows(2)
+	{
+		let (first, second) = (pair[0], pair[1]);
+		let (first_end, second_end) = (region_end(first)?, region_end(second)?);
+		ensure!(first_end <= second.object_offset,
+			"The {} region at [0x{:X}, 0x{:X}) overlaps the {} region at [0x{:X}, 0x{:X}).", first.region_type, first.object_offset, first_end,
+			second.region_type, second.object_offset, second_end);
+	}
+
+	return Ok(());
+}
+
+fn read_region_entry(data: &mut (impl Read + Seek), entry_offset: usize) -> Fallible<RegionTableEntry>
 {
 	data.seek(SeekFrom::Start(entry_offset as u64))?;
-	let mut entry = RegionTableEntry::new(data);
+	let mut entry = RegionTableEntry::new(data, entry_offset as u64)?;
 	match entry.object_id
 	{
 		REGION_BAT => {entry.region_type = RegionType::BAT}
@@ -129,27 +239,25 @@ fn read_region_entry(data: &mut File, entry_offset: usize) -> Fallible<RegionTab
 
 fn check_region_header_valid(data: &mut (impl Read + Seek), header_offset: usize, signature: &[u8], table: &RegionTable) -> Fallible<()>
 {
-	ensure!(signature == REGION_TAB_HEADER_SIG, "Region header signature is invalid.");
+	ensure!(signature == REGION_TAB_HEADER_SIG, BadSignature, "Region header signature is invalid.");
 
 	let mut header_buf: Vec<u8> = vec![0;REGION_TAB_LEN];
-	read_into(data, header_offset, &mut header_buf)?;
+	read_into(data, header_offset as u64, &mut header_buf)?;
 	header_buf[REGION_TAB_HEADER_SIG_LEN..(REGION_TAB_HEADER_SIG_LEN + REGION_TAB_HEADER_CHECKSUM_LEN)].as_mut().fill(0);
 	
-	check_checksum(header_buf, REGION_TAB_HEADER_SIG_LEN, table.checksum, "VHDX header")?;
-	ensure!(table.entry_count < MAX_REGION_ENTRIES,
-		format!("Region table entry count exceeds the specified maximum {}.", MAX_REGION_ENTRIES));
+	check_checksum(header_buf, REGION_TAB_HEADER_SIG_LEN, table.checksum, "Region table")?;
 	
 	return Ok(());
 }
 
-fn read_specific_region(data: &mut File, table_offset: usize) -> Fallible<RegionTable>
+fn read_specific_region(data: &mut (impl Read + Seek), table_offset: usize) -> Fallible<RegionTable>
 {
 	data.seek(SeekFrom::Start(table_offset as u64))?;
 
 	let mut signature:Vec<u8> = vec![0;REGION_TAB_HEADER_SIG_LEN];
 	signature.read_value(data)?;
 
-	let mut table = RegionTable::new(data);
+	let mut table = RegionTable::new(data, table_offset as u64)?;
 	
 	check_region_header_valid(data, table_offset, &signature, &table)?;
 
@@ -157,16 +265,130 @@ fn read_specific_region(data: &mut File, table_offset: usize) -> Fallible<Region
 	{
 		table.add_entry(read_region_entry(data, table_offset + REGION_TAB_HEADER_LEN + (n * REGION_TAB_ENTRY_LEN))?);
 	}
+	check_region_overlap(&table)?;
 
 	return Ok(table);
 }
 
-pub fn read_region(data: &mut File) -> Fallible<RegionTable>
+pub fn read_region(data: &mut (impl Read + Seek)) -> Fallible<(usize, RegionTable, bool)>
+{
+	let region1 = read_specific_region(data, FIRST_REGION_TAB_OFFSET);
+	let region2 = read_specific_region(data, SECOND_REGION_TAB_OFFSET);
+
+	// The copies can legitimately differ while an update is in progress, a valid copy is preferred over an invalid one.
+	match (region1, region2)
+	{
+		(Ok(region1), Ok(region2)) =>
+		{
+			let matched = region1 == region2;
+			return Ok((FIRST_REGION_TAB_OFFSET, region1, matched));
+		},
+		(Ok(region1), Err(_)) => return Ok((FIRST_REGION_TAB_OFFSET, region1, false)),
+		(Err(_), Ok(region2)) => return Ok((SECOND_REGION_TAB_OFFSET, region2, false)),
+		(Err(error1), Err(error2)) =>
+		{
+			return Err(VhdxError::BothCopiesInvalid{structure: String::from("region tables"), first_offset: FIRST_REGION_TAB_OFFSET,
+				first: Box::new(error1), second_offset: SECOND_REGION_TAB_OFFSET, second: Box::new(error2)});
+		},
+	}
+}
+
+pub fn recompute_region_checksums(data: &mut (impl Read + Seek)) -> Fallible<Vec<ChecksumFix>>
+{
+	return Ok(vec![
+		recompute_checksum(data, FIRST_REGION_TAB_OFFSET, REGION_TAB_LEN, &REGION_TAB_HEADER_SIG, "Region table")?,
+		recompute_checksum(data, SECOND_REGION_TAB_OFFSET, REGION_TAB_LEN, &REGION_TAB_HEADER_SIG, "Region table")?,
+	]);
+}
+
+pub fn check_region_in_file(entry: &RegionTableEntry, file_length: u64) -> Fallible<()>
 {
-	let region1 = read_specific_region(data, FIRST_REGION_TAB_OFFSET)?;
-	let region2 = read_specific_region(data, SECOND_REGION_TAB_OFFSET)?;
+	ensure!(entry.object_offset.checked_add(entry.object_length as u64).is_some_and(|x| x <= file_length),
+		"Region {} at offset 0x{:X} with length 0x{:X} extends past the end of the file (0x{:X} bytes).",
+		entry.object_id, entry.object_offset, entry.object_length, file_length);
+
+	return Ok(());
+}
+
+pub fn read_region_bytes(data: &mut (impl Read + Seek), entry: &RegionTableEntry) -> Fallible<Vec<u8>>
+{
+	check_region_in_file(entry, data.seek(SeekFrom::End(0))?)?;
+
+	let mut buffer: Vec<u8> = vec![0;entry.object_length as usize];
+	read_into(data, entry.object_offset, &mut buffer)?;
+
+	return Ok(buffer);
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::io::Cursor;
+
+	use super::*;
+
+	fn region_entry(region_type: RegionType, object_id: Uuid, object_offset: u64) -> RegionTableEntry
+	{
+		return RegionTableEntry{region_type, object_id, object_offset, object_length: REGION_SIZE_FACTOR, required: true};
+	}
+
+	#[test]
+	fn find_present_region()
+	{
+		let mut table = RegionTable::default();
+		table.add_entry(region_entry(RegionType::BAT, REGION_BAT, MIN_REGION_OFFSET));
+		table.add_entry(region_entry(RegionType::Metadata, REGION_METADATA, 2 * MIN_REGION_OFFSET));
+
+		assert_eq!(table.find(RegionType::Metadata).unwrap().object_offset, 2 * MIN_REGION_OFFSET);
+		assert_eq!(table.find_required(RegionType::BAT).unwrap().object_id, REGION_BAT);
+		assert_eq!(table.find_by_id(&REGION_METADATA).unwrap().region_type, RegionType::Metadata);
+	}
 
-	ensure!(region1 == region2, "Regions do not match.");
+	#[test]
+	fn find_absent_region()
+	{
+		let mut table = RegionTable::default();
+		table.add_entry(region_entry(RegionType::Metadata, REGION_METADATA, MIN_REGION_OFFSET));
 
-	return Ok(region1);
-}
\ No newline at end of file
+		assert!(table.find(RegionType::BAT).is_none());
+		assert_eq!(table.find_required(RegionType::BAT).unwrap_err().to_string(), "Required BAT region is absent.");
+		assert!(table.find_by_id(&REGION_BAT).is_none());
+	}
+
+	#[test]
+	fn overlap_check_survives_regions_at_the_top_of_the_address_space()
+	{
+		let top = u64::MAX - REGION_SIZE_FACTOR as u64 + 1;
+		let mut table = RegionTable::default();
+		table.add_entry(region_entry(RegionType::Unknown, Uuid::from_u128(1), top));
+		table.add_entry(region_entry(RegionType::Unknown, Uuid::from_u128(2), top));
+
+		let error = check_region_overlap(&table).unwrap_err();
+		assert!(error.to_string().contains("runs past the largest possible file offset"));
+
+		let mut table = RegionTable::default();
+		table.add_entry(region_entry(RegionType::BAT, REGION_BAT, MIN_REGION_OFFSET));
+		table.add_entry(region_entry(RegionType::Unknown, Uuid::from_u128(1), top - REGION_SIZE_FACTOR as u64));
+		assert!(check_region_overlap(&table).is_ok());
+	}
+
+	#[test]
+	fn region_table_round_trip()
+	{
+		let mut table = RegionTable::default();
+		table.add_entry(region_entry(RegionType::BAT, REGION_BAT, MIN_REGION_OFFSET));
+		table.add_entry(region_entry(RegionType::Metadata, REGION_METADATA, 2 * MIN_REGION_OFFSET));
+		table.add_entry(RegionTableEntry{object_id: Uuid::from_u128(1), object_offset: 3 * MIN_REGION_OFFSET, ..Default::default()});
+		table.entry_count = table.entries.len() as u32;
+
+		let mut data = Cursor::new(vec![0_u8; FIRST_REGION_TAB_OFFSET]);
+		data.set_position(FIRST_REGION_TAB_OFFSET as u64);
+		table.write_to(&mut data).unwrap();
+		assert_eq!(data.get_ref().len(), FIRST_REGION_TAB_OFFSET + REGION_TAB_LEN);
+
+		// The checksum is only known once written, everything else must come back as it went in.
+		let read = read_specific_region(&mut data, FIRST_REGION_TAB_OFFSET).unwrap();
+		table.checksum = read.checksum;
+		assert_eq!(read, table);
+	}
+}
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use byteorder::{BigEndian,ByteOrder};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::{Fallible,VhdxError};
+use crate::{ensure,format_err};
+use crate::VhdType;
+use crate::json;
+use crate::reader::{read_ascii,read_into};
+
+const VHD_FOOTER_LEN: usize = 0x200;
+const VHD_FOOTER_COOKIE: [u8; VHD_FOOTER_COOKIE_LEN] = [0x63, 0x6f, 0x6e, 0x65, 0x63, 0x74, 0x69, 0x78];
+const VHD_FOOTER_COOKIE_LEN: usize = 0x8;
+const VHD_FOOTER_FEATURES_OFFSET: usize = 0x8;
+const VHD_FOOTER_FORMAT_VERSION_OFFSET: usize = 0xc;
+const VHD_FOOTER_DATA_OFFSET_OFFSET: usize = 0x10;
+const VHD_FOOTER_CREATOR_APPLICATION_OFFSET: usize = 0x1c;
+const VHD_FOOTER_CREATOR_APPLICATION_LEN: usize = 0x4;
+const VHD_FOOTER_ORIGINAL_SIZE_OFFSET: usize = 0x28;
+const VHD_FOOTER_CURRENT_SIZE_OFFSET: usize = 0x30;
+const VHD_FOOTER_DISK_TYPE_OFFSET: usize = 0x3c;
+const VHD_FOOTER_CHECKSUM_OFFSET: usize = 0x40;
+const VHD_FOOTER_CHECKSUM_LEN: usize = 0x4;
+const VHD_FOOTER_UNIQUE_ID_OFFSET: usize = 0x44;
+const VHD_FOOTER_UNIQUE_ID_LEN: usize = 0x10;
+
+const VHD_DISK_TYPE_FIXED: u32 = 2;
+const VHD_DISK_TYPE_DYNAMIC: u32 = 3;
+const VHD_DISK_TYPE_DIFFERENCING: u32 = 4;
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct VhdFooter
+{
+	#[serde(serialize_with = "json::hex")]
+	pub features: u32,
+	#[serde(serialize_with = "json::hex")]
+	pub format_version: u32,
+	#[serde(serialize_with = "json::dec_hex")]
+	pub data_offset: u64,
+	pub creator_application: String,
+	#[serde(serialize_with = "json::dec_hex")]
+	pub original_size: u64,
+	#[serde(serialize_with = "json::dec_hex")]
+	pub current_size: u64,
+	pub disk_type: VhdType,
+	#[serde(serialize_with = "json::hex")]
+	pub checksum: u32,
+	pub unique_id: Uuid,
+}
+
+fn calculate_footer_checksum(footer_buf: &[u8]) -> u32
+{
+	// The checksum is the one's complement of the byte sum of the footer with the checksum field excluded.
+	let sum = footer_buf.iter().enumerate()
+		.filter(|(n, _)| !(VHD_FOOTER_CHECKSUM_OFFSET..(VHD_FOOTER_CHECKSUM_OFFSET + VHD_FOOTER_CHECKSUM_LEN)).contains(n))
+		.fold(0_u32, |sum, (_, x)| sum.wrapping_add(*x as u32));
+	return !sum;
+}
+
+fn parse_disk_type(value: u32) -> Fallible<VhdType>
+{
+	match value
+	{
+		VHD_DISK_TYPE_FIXED => return Ok(VhdType::Fixed),
+		VHD_DISK_TYPE_DYNAMIC => return Ok(VhdType::Dynamic),
+		VHD_DISK_TYPE_DIFFERENCING => return Ok(VhdType::Differencing),
+		_ => return Err(format_err!("VHD footer disk type {} is not recognised.", value)),
+	}
+}
+
+pub fn read_vhd_footer(data: &mut (impl Read + Seek)) -> Fallible<(u64, VhdFooter)>
+{
+	let file_length = data.seek(SeekFrom::End(0))?;
+	ensure!(file_length >= VHD_FOOTER_LEN as u64, "File is too short to contain a VHD footer.");
+	let footer_offset = file_length - VHD_FOOTER_LEN as u64;
+
+	let mut footer_buf: Vec<u8> = vec![0;VHD_FOOTER_LEN];
+	read_into(data, footer_offset, &mut footer_buf)?;
+	ensure!(footer_buf[..VHD_FOOTER_COOKIE_LEN] == VHD_FOOTER_COOKIE, BadSignature, "File signature is invalid and the file does not end with a VHD footer.");
+
+	let checksum = BigEndian::read_u32(&footer_buf[VHD_FOOTER_CHECKSUM_OFFSET..]);
+	let computed = calculate_footer_checksum(&footer_buf);
+	if checksum != computed
+	{
+		return Err(VhdxError::ChecksumMismatch{type_name: String::from("VHD footer"), expected: checksum, computed});
+	}
+
+	let creator_application = read_ascii(&mut &footer_buf[VHD_FOOTER_CREATOR_APPLICATION_OFFSET..], VHD_FOOTER_CREATOR_APPLICATION_LEN)?;
+
+	let footer = VhdFooter{
+		features: BigEndian::read_u32(&footer_buf[VHD_FOOTER_FEATURES_OFFSET..]),
+		format_version: BigEndian::read_u32(&footer_buf[VHD_FOOTER_FORMAT_VERSION_OFFSET..]),
+		data_offset: BigEndian::read_u64(&footer_buf[VHD_FOOTER_DATA_OFFSET_OFFSET..]),
+		creator_application,
+		original_size: BigEndian::read_u64(&footer_buf[VHD_FOOTER_ORIGINAL_SIZE_OFFSET..]),
+		current_size: BigEndian::read_u64(&footer_buf[VHD_FOOTER_CURRENT_SIZE_OFFSET..]),
+		disk_type: parse_disk_type(BigEndian::read_u32(&footer_buf[VHD_FOOTER_DISK_TYPE_OFFSET..]))?,
+		checksum,
+		unique_id: Uuid::from_slice(&footer_buf[VHD_FOOTER_UNIQUE_ID_OFFSET..(VHD_FOOTER_UNIQUE_ID_OFFSET + VHD_FOOTER_UNIQUE_ID_LEN)])?,
+	};
+
+	return Ok((footer_offset, footer));
+}
@@ -2,79 +2,128 @@
 // Copyright (c) Nick Moss.
 
 use std::{
-	fs::File,
+	fmt,
 	io::{Seek, Read, SeekFrom},
 };
 
-use failure::{ensure,Fallible};
+use serde::Serialize;
 use uuid::Uuid;
 
+use crate::error::{Fallible,VhdxError};
+use crate::ensure;
 use crate::checksum::*;
+use crate::json;
+use crate::maths::{format_offset,format_size};
 use crate::reader::{read_into,ReadValue};
 
-const FIRST_HEADER_OFFSET: usize = 0x10000;
-const SECOND_HEADER_OFFSET: usize = 0x20000;
+pub const FIRST_HEADER_OFFSET: usize = 0x10000;
+pub const SECOND_HEADER_OFFSET: usize = 0x20000;
 const VHD_HEADER_LEN: usize = 0x1000;
-const VHD_HEADER_SIG: [u8; VHD_HEADER_SIG_LEN] = [0x68, 0x65, 0x61, 0x64];
+pub const VHD_HEADER_SIG: [u8; VHD_HEADER_SIG_LEN] = [0x68, 0x65, 0x61, 0x64];
 const VHD_HEADER_SIG_LEN: usize = 0x4;
 const VHD_HEADER_CHECKSUM_LEN: usize = CHECKSUM_LENGTH;
+const SUPPORTED_VERSION: u16 = 1;
+const SUPPORTED_LOG_VERSION: u16 = 0;
 
-#[derive(PartialEq, Default)]
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
+pub struct CorruptHeader
+{
+	#[serde(serialize_with = "json::dec_hex")]
+	pub offset: usize,
+	pub error: String,
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct VhdHeader
 {
+	#[serde(serialize_with = "json::hex")]
 	pub checksum: u32,
+	#[serde(serialize_with = "json::hex")]
 	pub sequence_number: u64,
 	pub file_write_id: Uuid,
 	pub data_write_id: Uuid,
 	pub log_id: Uuid,
 	pub log_version: u16,
 	pub version: u16,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub log_length: u32,
+	#[serde(serialize_with = "json::dec_hex")]
 	pub log_offset: u64
 }
 
 impl VhdHeader
 {
-	pub fn new(data: &mut (impl Read + Seek)) -> Self
+	pub fn new(data: &mut (impl Read + Seek), offset: u64) -> Fallible<Self>
 	{
 		let mut result = VhdHeader::default();
 		
-		result.checksum.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header checksum value: {:?}", error)});
-		result.sequence_number.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header sequence number value: {:?}", error)});
-		result.file_write_id.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header file write id value: {:?}", error)});
-		result.data_write_id.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header data write id value: {:?}", error)});
-		result.log_id.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header log id value: {:?}", error)});
-		result.log_version.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header log version value: {:?}", error)});
-		result.version.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header version value: {:?}", error)});
-		result.log_length.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header log length value: {:?}", error)});
-		result.log_offset.read_value(data).unwrap_or_else(|error| {
-			panic!("Failed to read VHDX Header log offset value: {:?}", error)});
+		result.checksum.read_value(data).map_err(|error| VhdxError::read("VHDX Header checksum value", offset + 0x4, error))?;
+		result.sequence_number.read_value(data).map_err(|error| VhdxError::read("VHDX Header sequence number value", offset + 0x8, error))?;
+		result.file_write_id.read_value(data).map_err(|error| VhdxError::read("VHDX Header file write id value", offset + 0x10, error))?;
+		result.data_write_id.read_value(data).map_err(|error| VhdxError::read("VHDX Header data write id value", offset + 0x20, error))?;
+		result.log_id.read_value(data).map_err(|error| VhdxError::read("VHDX Header log id value", offset + 0x30, error))?;
+		result.log_version.read_value(data).map_err(|error| VhdxError::read("VHDX Header log version value", offset + 0x40, error))?;
+		result.version.read_value(data).map_err(|error| VhdxError::read("VHDX Header version value", offset + 0x42, error))?;
+		result.log_length.read_value(data).map_err(|error| VhdxError::read("VHDX Header log length value", offset + 0x44, error))?;
+		result.log_offset.read_value(data).map_err(|error| VhdxError::read("VHDX Header log offset value", offset + 0x48, error))?;
 		
-		return result;
+		return Ok(result);
+	}
+
+	pub fn is_supported_version(self: &Self) -> bool
+	{
+		return self.version == SUPPORTED_VERSION;
+	}
+
+	pub fn is_supported_log_version(self: &Self) -> bool
+	{
+		return self.log_version == SUPPORTED_LOG_VERSION;
+	}
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum HeaderCopy
+{
+	First,
+	Second,
+}
+
+impl fmt::Display for HeaderCopy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			HeaderCopy::First => write!(f, "first"),
+			HeaderCopy::Second => write!(f, "second"),
+		}
+	}
+}
+
+impl fmt::Display for VhdHeader {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "	Checksum is				0x{:X}.", self.checksum)?;
+		writeln!(f, "	Current sequence number is		0x{:X}.", self.sequence_number)?;
+		writeln!(f, "	File Write GUID is			{}.", self.file_write_id)?;
+		writeln!(f, "	Data Write GUID is			{}.", self.data_write_id)?;
+		writeln!(f, "	Log GUID is				{}.", self.log_id)?;
+		writeln!(f, "	Log version is				{}.", self.log_version)?;
+		writeln!(f, "	Version is				{}.", self.version)?;
+		writeln!(f, "	Log length is				{}.", format_size(self.log_length as u64))?;
+		writeln!(f, "	Log Offset is				{}.", format_offset(self.log_offset, f.alternate()))
 	}
 }
 
 fn check_vhdx_header_valid(data: &mut (impl Read + Seek), header_offset: usize, checksum: u32, signature: &[u8]) -> Fallible<()>
 {
-	ensure!(signature == VHD_HEADER_SIG, "VHDX header signature is invalid.");
+	ensure!(signature == VHD_HEADER_SIG, BadSignature, "VHDX header signature is invalid.");
 
 	let mut header_buf: Vec<u8> = vec![0;VHD_HEADER_LEN];
-	read_into(data, header_offset, &mut header_buf)?;
+	read_into(data, header_offset as u64, &mut header_buf)?;
 	header_buf[VHD_HEADER_SIG_LEN..(VHD_HEADER_SIG_LEN + VHD_HEADER_CHECKSUM_LEN)].as_mut().fill(0);
 	
 	check_checksum(header_buf, VHD_HEADER_SIG_LEN, checksum, "VHDX header")?;
 	return Ok(());
 }
 
-fn read_specific_vhdx_header(data: &mut File, header_offset: usize) -> Fallible<VhdHeader>
+fn read_specific_vhdx_header(data: &mut (impl Read + Seek), header_offset: usize) -> Fallible<VhdHeader>
 {
 	data.seek(SeekFrom::Start(header_offset as u64))?;
 
@@ -87,22 +136,70 @@ fn read_specific_vhdx_header(data: &mut File, header_offset: usize) -> Fallible<
 
 	data.seek(SeekFrom::Start((header_offset + VHD_HEADER_SIG_LEN) as u64))?;
 
-	return Ok(VhdHeader::new(data));
+	return VhdHeader::new(data, header_offset as u64);
 }
 
-pub fn read_vhdx_header(data: &mut File) -> Fallible<(usize, VhdHeader)>
+pub fn read_vhdx_header(data: &mut (impl Read + Seek)) -> Fallible<(usize, VhdHeader, Option<CorruptHeader>, Option<u64>)>
 {
-	let header1 = read_specific_vhdx_header(data, FIRST_HEADER_OFFSET)?;
-	let header2 = read_specific_vhdx_header(data, SECOND_HEADER_OFFSET)?;
-
-	ensure!(header1.sequence_number != header2.sequence_number, "Header sequence numbers are identical.");
+	let header1 = read_specific_vhdx_header(data, FIRST_HEADER_OFFSET);
+	let header2 = read_specific_vhdx_header(data, SECOND_HEADER_OFFSET);
 
-	if header1.sequence_number > header2.sequence_number
+	// A single corrupt copy is expected after an interrupted header update, the valid copy is used on its own.
+	match (header1, header2)
 	{
-		return Ok((FIRST_HEADER_OFFSET, header1));
+		(Ok(header1), Ok(header2)) =>
+		{
+			ensure!(header1.sequence_number != header2.sequence_number, "Header sequence numbers are identical.");
+
+			// The sequence number of the unused copy is kept so the caller can check how far apart the two are.
+			if header1.sequence_number > header2.sequence_number
+			{
+				let other_sequence_number = header2.sequence_number;
+				return Ok((FIRST_HEADER_OFFSET, header1, None, Some(other_sequence_number)));
+			}
+			else
+			{
+				let other_sequence_number = header1.sequence_number;
+				return Ok((SECOND_HEADER_OFFSET, header2, None, Some(other_sequence_number)));
+			}
+		},
+		(Ok(header1), Err(error)) =>
+		{
+			return Ok((FIRST_HEADER_OFFSET, header1, Some(CorruptHeader{offset: SECOND_HEADER_OFFSET, error: error.to_string()}), None));
+		},
+		(Err(error), Ok(header2)) =>
+		{
+			return Ok((SECOND_HEADER_OFFSET, header2, Some(CorruptHeader{offset: FIRST_HEADER_OFFSET, error: error.to_string()}), None));
+		},
+		(Err(error1), Err(error2)) =>
+		{
+			return Err(VhdxError::BothCopiesInvalid{structure: String::from("VHDX headers"), first_offset: FIRST_HEADER_OFFSET,
+				first: Box::new(error1), second_offset: SECOND_HEADER_OFFSET, second: Box::new(error2)});
+		},
 	}
-	else
+}
+
+pub fn recompute_vhdx_header_checksums(data: &mut (impl Read + Seek)) -> Fallible<Vec<ChecksumFix>>
+{
+	return Ok(vec![
+		recompute_checksum(data, FIRST_HEADER_OFFSET, VHD_HEADER_LEN, &VHD_HEADER_SIG, "VHDX header")?,
+		recompute_checksum(data, SECOND_HEADER_OFFSET, VHD_HEADER_LEN, &VHD_HEADER_SIG, "VHDX header")?,
+	]);
+}
+
+pub fn read_forced_vhdx_header(data: &mut (impl Read + Seek), copy: HeaderCopy) -> Fallible<(usize, VhdHeader, Option<CorruptHeader>, Option<u64>)>
+{
+	let (header_offset, other_offset) = match copy
 	{
-		return Ok((SECOND_HEADER_OFFSET, header2));
+		HeaderCopy::First => (FIRST_HEADER_OFFSET, SECOND_HEADER_OFFSET),
+		HeaderCopy::Second => (SECOND_HEADER_OFFSET, FIRST_HEADER_OFFSET),
+	};
+	let header = read_specific_vhdx_header(data, header_offset)?;
+
+	// The other copy is only read to report on, it is not used even when its sequence number is higher.
+	match read_specific_vhdx_header(data, other_offset)
+	{
+		Ok(other) => return Ok((header_offset, header, None, Some(other.sequence_number))),
+		Err(error) => return Ok((header_offset, header, Some(CorruptHeader{offset: other_offset, error: error.to_string()}), None)),
 	}
-}
\ No newline at end of file
+}
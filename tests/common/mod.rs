@@ -0,0 +1,264 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+// Each test file pulls this in with its own `mod common`, so not every helper is used by every file.
+#![allow(dead_code)]
+
+use crc32c::crc32c;
+use uuid::{Uuid,uuid};
+
+pub const MIB: u64 = u64::pow(1024, 2);
+
+const FIRST_HEADER_OFFSET: usize = 0x10000;
+const SECOND_HEADER_OFFSET: usize = 0x20000;
+const FIRST_REGION_TAB_OFFSET: usize = 0x30000;
+const SECOND_REGION_TAB_OFFSET: usize = 0x40000;
+const REGION_TAB_LEN: usize = 0x10000;
+const REGION_TAB_HEADER_LEN: usize = 0x10;
+const REGION_TAB_ENTRY_LEN: usize = 0x20;
+const METADATA_TABLE_LEN: usize = 0x10000;
+const METADATA_HEADER_LEN: usize = 0x20;
+const METADATA_ENTRY_LEN: usize = 0x20;
+const PARENT_LOCATOR_HEADER_LEN: usize = 0x14;
+const PARENT_LOCATOR_ENTRY_LEN: usize = 0xc;
+const CHUNK_RATIO_MULTIPLIER: u64 = u64::pow(2, 23);
+
+const REGION_BAT: Uuid = uuid!("2DC27766-F623-4200-9D64-115E9BFD4A08");
+const REGION_METADATA: Uuid = uuid!("8B7CA206-4790-4B9A-B8FE-575F050F886E");
+const METADATA_FILE_PARAMETERS: Uuid = uuid!("CAA16737-FA36-4D43-B3B6-33F0AA44E76B");
+const METADATA_VIRTUAL_DISK_SIZE: Uuid = uuid!("2FA54224-CD1B-4876-B211-5DBED83BF4B8");
+const METADATA_VIRTUAL_DISK_ID: Uuid = uuid!("BECA12AB-B2E6-4523-93EF-C309E000C746");
+const METADATA_LOGICAL_SECTOR_SIZE: Uuid = uuid!("8141BF1D-A96F-4709-BA47-F233A8FAAB5F");
+const METADATA_PHYSICAL_SECTOR_SIZE: Uuid = uuid!("CDA348C7-445D-4471-9CC9-E9885251C556");
+const METADATA_PARENT_LOCATOR: Uuid = uuid!("A8D35F2D-B30B-454D-ABF7-D3D84834AB0C");
+const METADATA_PARENT_LOCATOR_VHDX: Uuid = uuid!("B04AEFB7-D19E-4A81-B789-25B8E9445913");
+
+const METADATA_IS_VIRTUAL_DISK_FLAG: u32 = 0b00000010;
+const METADATA_IS_REQUIRED_FLAG: u32 = 0b00000100;
+const METADATA_HAS_PARENT_FLAG: u32 = 0b00000010;
+const BAT_STATE_FULLY_PRESENT: u64 = 6;
+
+pub const PARENT_RELATIVE_PATH: &str = ".\\parent.vhdx";
+
+// Builds a minimal VHDX image in memory. The knobs are public fields, set them on a default builder before calling build.
+#[derive(Clone, Debug)]
+pub struct VhdxBuilder
+{
+	pub logical_sector_size: u32,
+	pub physical_sector_size: u32,
+	pub block_size: u32,
+	pub virtual_disk_size: u64,
+	pub data_write_id: Uuid,
+	pub virtual_disk_id: Uuid,
+	// Present blocks get 1 MiB aligned space after the metadata region, in the order given.
+	pub present_blocks: Vec<u64>,
+	// A parent locator naming this data write GUID is added and the disk is marked as differencing.
+	pub parent_linkage: Option<Uuid>,
+	pub metadata_before_bat: bool,
+	// Item values are written in the reverse order of their table entries.
+	pub reverse_metadata_items: bool,
+	// Unknown, optional, zero length region entries added after the BAT and metadata entries. The count is written
+	// even when the entries no longer fit in the 64 KiB table, so the bound in the parser can be exercised.
+	pub extra_region_entries: u32,
+}
+
+impl Default for VhdxBuilder
+{
+	fn default() -> Self
+	{
+		return VhdxBuilder{logical_sector_size: 512, physical_sector_size: 4096, block_size: 32 * MIB as u32, virtual_disk_size: 64 * MIB,
+			data_write_id: uuid!("D0D0D0D0-0000-4000-8000-000000000001"), virtual_disk_id: uuid!("11111111-2222-3333-4444-555555555555"),
+			present_blocks: Vec::new(), parent_linkage: None, metadata_before_bat: false, reverse_metadata_items: false, extra_region_entries: 0};
+	}
+}
+
+pub struct BuiltLayout
+{
+	pub bat_offset: u64,
+	pub bat_length: u64,
+	pub metadata_offset: u64,
+	pub metadata_length: u64,
+	pub payload_offset: u64,
+}
+
+fn put(image: &mut [u8], offset: usize, bytes: &[u8]) -> ()
+{
+	image[offset..(offset + bytes.len())].copy_from_slice(bytes);
+}
+
+fn utf16(value: &str) -> Vec<u8>
+{
+	return value.encode_utf16().flat_map(|x| x.to_le_bytes()).collect();
+}
+
+// Checksums cover the whole structure with the 4 byte field after the signature zeroed.
+pub fn set_checksum(image: &mut [u8], offset: usize, length: usize) -> ()
+{
+	put(image, offset + 4, &[0; 4]);
+	let checksum = crc32c(&image[offset..(offset + length)]);
+	put(image, offset + 4, &checksum.to_le_bytes());
+}
+
+fn round_up(value: u64, alignment: u64) -> u64
+{
+	return value.div_ceil(alignment) * alignment;
+}
+
+impl VhdxBuilder
+{
+	pub fn chunk_ratio(self: &Self) -> u64
+	{
+		return (CHUNK_RATIO_MULTIPLIER * self.logical_sector_size as u64) / self.block_size as u64;
+	}
+
+	pub fn payload_blocks(self: &Self) -> u64
+	{
+		return self.virtual_disk_size.div_ceil(self.block_size as u64);
+	}
+
+	// The BAT is always sized for the padded differencing layout, which is never smaller than the dynamic one.
+	fn bat_entries(self: &Self) -> u64
+	{
+		return self.payload_blocks().div_ceil(self.chunk_ratio()) * (self.chunk_ratio() + 1);
+	}
+
+	pub fn layout(self: &Self) -> BuiltLayout
+	{
+		let bat_length = round_up((self.bat_entries() * 8).max(1), MIB);
+		let metadata_length = MIB;
+		let (bat_offset, metadata_offset) = match self.metadata_before_bat
+		{
+			true => (MIB + metadata_length, MIB),
+			false => (MIB, MIB + bat_length),
+		};
+		return BuiltLayout{bat_offset, bat_length, metadata_offset, metadata_length, payload_offset: MIB + bat_length + metadata_length};
+	}
+
+	pub fn build(self: &Self) -> Vec<u8>
+	{
+		let layout = self.layout();
+		let file_length = layout.payload_offset + (self.present_blocks.len() as u64 * round_up(self.block_size as u64, MIB));
+		let mut image: Vec<u8> = vec![0; file_length as usize];
+
+		put(&mut image, 0, b"vhdxfile");
+		put(&mut image, 8, &utf16("vhdx_inspector tests"));
+
+		// The second copy has the higher sequence number and is the one the parser picks.
+		for (offset, sequence_number) in [(FIRST_HEADER_OFFSET, 1_u64), (SECOND_HEADER_OFFSET, 2_u64)]
+		{
+			put(&mut image, offset, b"head");
+			put(&mut image, offset + 0x8, &sequence_number.to_le_bytes());
+			put(&mut image, offset + 0x10, &uuid!("F0F0F0F0-0000-4000-8000-000000000001").to_bytes_le());
+			put(&mut image, offset + 0x20, &self.data_write_id.to_bytes_le());
+			put(&mut image, offset + 0x42, &1_u16.to_le_bytes());
+			put(&mut image, offset + 0x44, &(MIB as u32).to_le_bytes());
+			put(&mut image, offset + 0x48, &MIB.to_le_bytes());
+			set_checksum(&mut image, offset, 0x1000);
+		}
+
+		let mut regions = vec![(REGION_BAT, layout.bat_offset, layout.bat_length as u32, 1_u32),
+			(REGION_METADATA, layout.metadata_offset, layout.metadata_length as u32, 1_u32)];
+		for n in 0..self.extra_region_entries
+		{
+			regions.push((Uuid::from_u128(n as u128 + 1), file_length, 0, 0));
+		}
+		for offset in [FIRST_REGION_TAB_OFFSET, SECOND_REGION_TAB_OFFSET]
+		{
+			put(&mut image, offset, b"regi");
+			put(&mut image, offset + 0x8, &(regions.len() as u32).to_le_bytes());
+			let fitting = (REGION_TAB_LEN - REGION_TAB_HEADER_LEN) / REGION_TAB_ENTRY_LEN;
+			for (n, (object_id, object_offset, object_length, required)) in regions.iter().take(fitting).enumerate()
+			{
+				let entry_offset = offset + REGION_TAB_HEADER_LEN + (n * REGION_TAB_ENTRY_LEN);
+				put(&mut image, entry_offset, &object_id.to_bytes_le());
+				put(&mut image, entry_offset + 0x10, &object_offset.to_le_bytes());
+				put(&mut image, entry_offset + 0x18, &object_length.to_le_bytes());
+				put(&mut image, entry_offset + 0x1c, &required.to_le_bytes());
+			}
+			set_checksum(&mut image, offset, REGION_TAB_LEN);
+		}
+
+		self.write_metadata(&mut image, layout.metadata_offset as usize);
+
+		// Each chunk_ratio payload entries are followed by a sector bitmap entry, which is left not present.
+		for (n, block) in self.present_blocks.iter().enumerate()
+		{
+			let entry_index = block + (block / self.chunk_ratio());
+			let file_offset_mb = (layout.payload_offset + (n as u64 * round_up(self.block_size as u64, MIB))) / MIB;
+			let entry = (file_offset_mb << 20) | BAT_STATE_FULLY_PRESENT;
+			put(&mut image, (layout.bat_offset + (entry_index * 8)) as usize, &entry.to_le_bytes());
+		}
+
+		return image;
+	}
+
+	fn parent_locator(self: &Self, parent_linkage: Uuid) -> Vec<u8>
+	{
+		let pairs = [(String::from("parent_linkage"), format!("{{{}}}", parent_linkage.to_string().to_uppercase())),
+			(String::from("relative_path"), String::from(PARENT_RELATIVE_PATH))];
+
+		let mut locator: Vec<u8> = vec![0; PARENT_LOCATOR_HEADER_LEN + (pairs.len() * PARENT_LOCATOR_ENTRY_LEN)];
+		put(&mut locator, 0, &METADATA_PARENT_LOCATOR_VHDX.to_bytes_le());
+		put(&mut locator, 0x12, &(pairs.len() as u16).to_le_bytes());
+		for (n, (key, value)) in pairs.iter().enumerate()
+		{
+			let (key, value) = (utf16(key), utf16(value));
+			let entry_offset = PARENT_LOCATOR_HEADER_LEN + (n * PARENT_LOCATOR_ENTRY_LEN);
+			let key_offset = locator.len();
+			put(&mut locator, entry_offset, &(key_offset as u32).to_le_bytes());
+			put(&mut locator, entry_offset + 0x4, &((key_offset + key.len()) as u32).to_le_bytes());
+			put(&mut locator, entry_offset + 0x8, &(key.len() as u16).to_le_bytes());
+			put(&mut locator, entry_offset + 0xa, &(value.len() as u16).to_le_bytes());
+			locator.extend_from_slice(&key);
+			locator.extend_from_slice(&value);
+		}
+
+		return locator;
+	}
+
+	fn write_metadata(self: &Self, image: &mut [u8], region_offset: usize) -> ()
+	{
+		let file_parameter_flags = match self.parent_linkage
+		{
+			Some(_) => METADATA_HAS_PARENT_FLAG,
+			None => 0,
+		};
+		let mut file_parameters = self.block_size.to_le_bytes().to_vec();
+		file_parameters.extend_from_slice(&file_parameter_flags.to_le_bytes());
+
+		let required = METADATA_IS_REQUIRED_FLAG;
+		let disk_required = METADATA_IS_REQUIRED_FLAG | METADATA_IS_VIRTUAL_DISK_FLAG;
+		let mut items = vec![
+			(METADATA_FILE_PARAMETERS, required, file_parameters),
+			(METADATA_VIRTUAL_DISK_SIZE, disk_required, self.virtual_disk_size.to_le_bytes().to_vec()),
+			(METADATA_VIRTUAL_DISK_ID, disk_required, self.virtual_disk_id.to_bytes_le().to_vec()),
+			(METADATA_LOGICAL_SECTOR_SIZE, disk_required, self.logical_sector_size.to_le_bytes().to_vec()),
+			(METADATA_PHYSICAL_SECTOR_SIZE, disk_required, self.physical_sector_size.to_le_bytes().to_vec()),
+		];
+		if let Some(parent_linkage) = self.parent_linkage
+		{
+			items.push((METADATA_PARENT_LOCATOR, required, self.parent_locator(parent_linkage)));
+		}
+
+		put(image, region_offset, b"metadata");
+		put(image, region_offset + 0xa, &(items.len() as u16).to_le_bytes());
+
+		let mut value_order: Vec<usize> = (0..items.len()).collect();
+		if self.reverse_metadata_items
+		{
+			value_order.reverse();
+		}
+		let mut value_offset = METADATA_TABLE_LEN;
+		for n in value_order
+		{
+			let (object_id, flags, value) = &items[n];
+			let entry_offset = region_offset + METADATA_HEADER_LEN + (n * METADATA_ENTRY_LEN);
+			put(image, entry_offset, &object_id.to_bytes_le());
+			put(image, entry_offset + 0x10, &(value_offset as u32).to_le_bytes());
+			put(image, entry_offset + 0x14, &(value.len() as u32).to_le_bytes());
+			put(image, entry_offset + 0x18, &flags.to_le_bytes());
+			put(image, region_offset + value_offset, value);
+			value_offset += value.len().next_multiple_of(8);
+		}
+	}
+}
@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+mod common;
+
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use vhdx_inspector::VhdxFile;
+
+use common::{MIB,VhdxBuilder};
+
+// Writes the seeds for fuzz/corpus/parse, which is not kept in the repository. Run it with `cargo test --test fuzz_seeds -- --ignored`.
+#[test]
+#[ignore]
+fn write_fuzz_seeds()
+{
+	let mut seeds: Vec<(&str, VhdxBuilder)> = Vec::new();
+	seeds.push(("default", VhdxBuilder::default()));
+
+	let mut builder = VhdxBuilder::default();
+	builder.logical_sector_size = 4096;
+	builder.block_size = MIB as u32;
+	builder.virtual_disk_size = 3 * MIB;
+	seeds.push(("4k_sectors", builder));
+
+	let mut builder = VhdxBuilder::default();
+	builder.block_size = MIB as u32;
+	builder.present_blocks = vec![0, 5, 63];
+	seeds.push(("present_blocks", builder));
+
+	let mut builder = VhdxBuilder::default();
+	builder.parent_linkage = Some(builder.data_write_id);
+	seeds.push(("differencing", builder));
+
+	let mut builder = VhdxBuilder::default();
+	builder.metadata_before_bat = true;
+	builder.reverse_metadata_items = true;
+	seeds.push(("metadata_first", builder));
+
+	let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/parse");
+	fs::create_dir_all(&directory).unwrap();
+	for (name, builder) in seeds
+	{
+		let image = builder.build();
+		VhdxFile::from_reader(Cursor::new(image.clone()), true).unwrap();
+
+		// The fuzz target pads inputs back out with zeros, so the trailing ones only slow the fuzzer down.
+		let length = image.iter().rposition(|x| *x != 0).map_or(0, |x| x + 1);
+		fs::write(directory.join(name), &image[..length]).unwrap();
+	}
+}
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: MIT
+// Copyright (c) Nick Moss.
+
+mod common;
+
+use std::io::Cursor;
+
+use vhdx_inspector::VhdxFile;
+use vhdx_inspector::block::{self,PayloadBlockState};
+use vhdx_inspector::metadata;
+use vhdx_inspector::region::{self,RegionType};
+
+use common::{MIB,VhdxBuilder};
+
+#[test]
+fn default_image_parses()
+{
+	let builder = VhdxBuilder::default();
+	let vhdx = VhdxFile::from_reader(Cursor::new(builder.build()), true).unwrap();
+
+	assert_eq!(vhdx.metadata.virtual_disk_size, 64 * MIB);
+	assert_eq!(vhdx.metadata.logical_sector_size, 512);
+	assert_eq!(vhdx.metadata.physical_sector_size, 4096);
+	assert_eq!(vhdx.metadata.file_parameters.block_size, 32 * MIB as u32);
+	assert_eq!(vhdx.metadata.virtual_disk_id, builder.virtual_disk_id);
+	assert_eq!(vhdx.vhdx_header.data_write_id, builder.data_write_id);
+	assert_eq!(vhdx.vhdx_header.sequence_number, 2);
+	assert!(vhdx.region_tables_match);
+	assert!(vhdx.metadata.parent_locator.is_none());
+	assert_eq!(vhdx.payload_blocks.len(), 2);
+	assert!(vhdx.payload_blocks.iter().all(|x| x.state == PayloadBlockState::NotPresent));
+}
+
+#[test]
+fn stages_parse_separately()
+{
+	let mut builder = VhdxBuilder::default();
+	builder.logical_sector_size = 4096;
+	builder.block_size = MIB as u32;
+	builder.virtual_disk_size = 3 * MIB;
+	builder.present_blocks = vec![0, 2];
+	let layout = builder.layout();
+	let mut data = Cursor::new(builder.build());
+
+	let (_, region_table, matched) = region::read_region(&mut data).unwrap();
+	assert!(matched);
+	let bat_region = region_table.entries.iter().find(|x| x.region_type == RegionType::BAT).unwrap();
+	let metadata_region = region_table.entries.iter().find(|x| x.region_type == RegionType::Metadata).unwrap();
+	assert_eq!(bat_region.object_offset, layout.bat_offset);
+	assert_eq!(metadata_region.object_offset, layout.metadata_offset);
+
+	let (table, metadata) = metadata::read_metadata(&mut data, metadata_region).unwrap();
+	assert_eq!(table.entries.len(), 5);
+	assert_eq!(metadata.logical_sector_size, 4096);
+	assert_eq!(metadata.virtual_disk_size, 3 * MIB);
+
+	let file_length = data.get_ref().len() as u64;
+	let (payload_blocks, _) = block::read_bat(&mut data, bat_region, &metadata, Some(file_length)).unwrap();
+	assert_eq!(payload_blocks.len(), 3);
+	assert!(payload_blocks[0].state == PayloadBlockState::FullyPresent);
+	assert!(payload_blocks[1].state == PayloadBlockState::NotPresent);
+	assert!(payload_blocks[2].state == PayloadBlockState::FullyPresent);
+	assert_eq!(payload_blocks[0].file_offset_mb, layout.payload_offset / MIB);
+	assert_eq!(payload_blocks[2].file_offset_mb, layout.payload_offset / MIB + 1);
+}
+
+#[test]
+fn differencing_image_parses()
+{
+	let mut builder = VhdxBuilder::default();
+	builder.parent_linkage = Some(builder.data_write_id);
+	let vhdx = VhdxFile::from_reader(Cursor::new(builder.build()), true).unwrap();
+
+	let locator = vhdx.metadata.parent_locator.unwrap();
+	assert!(vhdx.metadata.file_parameters.has_parent);
+	assert_eq!(locator.parent_linkage, builder.data_write_id);
+	assert_eq!(locator.relative_path, common::PARENT_RELATIVE_PATH);
+}
+
+#[test]
+fn zero_physical_sector_size_is_defaulted()
+{
+	let mut builder = VhdxBuilder::default();
+	builder.logical_sector_size = 4096;
+	builder.physical_sector_size = 0;
+	let vhdx = VhdxFile::from_reader(Cursor::new(builder.build()), false).unwrap();
+
+	assert_eq!(vhdx.metadata.physical_sector_size, 4096);
+	assert!(vhdx.metadata.physical_sector_size >= vhdx.metadata.logical_sector_size);
+	assert!(vhdx.metadata.physical_sector_size_assumed);
+}
+
+#[test]
+fn metadata_region_before_bat_with_reversed_items()
+{
+	let mut builder = VhdxBuilder::default();
+	builder.metadata_before_bat = true;
+	builder.reverse_metadata_items = true;
+	builder.present_blocks = vec![1];
+	let layout = builder.layout();
+	assert!(layout.metadata_offset < layout.bat_offset);
+
+	let vhdx = VhdxFile::from_reader(Cursor::new(builder.build()), true).unwrap();
+	let offsets: Vec<u32> = vhdx.metadata_table.entries.iter().map(|x| x.object_offset).collect();
+	assert!(offsets.windows(2).all(|x| x[0] > x[1]));
+
+	assert_eq!(vhdx.region(RegionType::Metadata).unwrap().object_offset, layout.metadata_offset);
+	assert_eq!(vhdx.metadata.virtual_disk_size, builder.virtual_disk_size);
+	assert_eq!(vhdx.metadata.virtual_disk_id, builder.virtual_disk_id);
+	assert_eq!(vhdx.metadata.file_parameters.block_size, builder.block_size);
+	assert_eq!(vhdx.payload_blocks[1].state, PayloadBlockState::FullyPresent);
+}
+
+#[test]
+fn zero_virtual_disk_size_is_an_error()
+{
+	let mut builder = VhdxBuilder::default();
+	builder.virtual_disk_size = 0;
+
+	let error = VhdxFile::from_reader(Cursor::new(builder.build()), true).err().unwrap();
+	assert_eq!(error.exit_code(), vhdx_inspector::error::EXIT_INVALID_METADATA);
+}
+
+#[test]
+fn forty_gib_dynamic_bat()
+{
+	let mut builder = VhdxBuilder::default();
+	builder.virtual_disk_size = 40 * 1024 * MIB;
+	builder.present_blocks = vec![0, 127, 128, 1279];
+	let vhdx = VhdxFile::from_reader(Cursor::new(builder.build()), true).unwrap();
+
+	assert_eq!(vhdx.payload_blocks.len(), 1280);
+	assert_eq!(vhdx.sector_blocks.len(), 9);
+	let present: Vec<usize> = vhdx.payload_blocks.iter().enumerate()
+		.filter(|(_, x)| x.state == PayloadBlockState::FullyPresent).map(|(n, _)| n).collect();
+	assert_eq!(present, [0, 127, 128, 1279]);
+}
+
+#[test]
+fn region_entry_count_boundary()
+{
+	assert_eq!(region::MAX_REGION_ENTRIES, 2047);
+
+	// The BAT and metadata entries plus the extra ones fill the 64 KiB table exactly.
+	let mut builder = VhdxBuilder::default();
+	builder.extra_region_entries = region::MAX_REGION_ENTRIES - 2;
+	let (_, region_table, _) = region::read_region(&mut Cursor::new(builder.build())).unwrap();
+	assert_eq!(region_table.entry_count, region::MAX_REGION_ENTRIES);
+	assert_eq!(region_table.entries.len(), region::MAX_REGION_ENTRIES as usize);
+	assert!(region_table.find(RegionType::Metadata).is_some());
+
+	builder.extra_region_entries += 1;
+	assert!(region::read_region(&mut Cursor::new(builder.build())).is_err());
+}